@@ -0,0 +1,117 @@
+#!/usr/bin/env python
+#
+# This file is part of the clcache project.
+#
+# The contents of this file are subject to the BSD 3-Clause License, the
+# full text of which is available in the accompanying LICENSE file at the
+# root directory of this project.
+#
+# Measures how fast clcachesrv could hash files on this machine: each hash
+# algorithm it knows against each way of reading files (at once, in chunks of
+# various sizes, memory mapped), on synthetic headers and on large files. Use
+# it when tuning HashCache.CHUNK_SIZE and HashCache.STREAMING_THRESHOLD.
+#
+# The files are read once before measuring, so the numbers are those of files
+# in the file system cache, as most headers are during a build.
+#
+import argparse
+import mmap
+import os
+import random
+import sys
+import tempfile
+import timeit
+
+sys.path.insert(0, os.path.dirname(__file__))
+
+from clcache.server.__main__ import HASHERS, HashCache  # pylint: disable=wrong-import-position
+
+
+def createDataset(directory, name, count, minSize, maxSize):
+    paths = []
+    for i in range(count):
+        path = os.path.join(directory, '{}{:04d}'.format(name, i))
+        with open(path, 'wb') as f:
+            f.write(os.urandom(random.randint(minSize, maxSize)))
+        paths.append(path)
+    return paths
+
+
+def readAtOnce(path, hasher):
+    with open(path, 'rb') as f:
+        hasher.update(f.read())
+
+
+def readInChunks(chunkSize):
+    def read(path, hasher):
+        with open(path, 'rb') as f:
+            for chunk in iter(lambda: f.read(chunkSize), b''):
+                hasher.update(chunk)
+    return read
+
+
+def readMapped(path, hasher):
+    with open(path, 'rb') as f:
+        if os.fstat(f.fileno()).st_size == 0:
+            return
+        with mmap.mmap(f.fileno(), 0, access=mmap.ACCESS_READ) as m:
+            hasher.update(m)
+
+
+def measure(paths, createHasher, read, repeat):
+    """Returns the best throughput (MB/s) of hashing all paths out of repeat runs"""
+    def run():
+        for path in paths:
+            hasher = createHasher()
+            read(path, hasher)
+            hasher.hexdigest()
+    size = sum(os.path.getsize(path) for path in paths)
+    return size / (1024 * 1024) / min(timeit.repeat(run, number=1, repeat=repeat))
+
+
+def main():
+    parser = argparse.ArgumentParser(description='Benchmark the ways clcachesrv could hash files.')
+    parser.add_argument('--headers', metavar='N', type=int, default=2000, help='Number of header sized files.')
+    parser.add_argument('--large-files', metavar='N', type=int, default=4, help='Number of large files.')
+    parser.add_argument('--large-size', metavar='MB', type=int, default=64, help='Size of the large files.')
+    parser.add_argument('--chunk-size', metavar='KB', type=int, action='append',
+                        help='Chunk size to read files in; may be given several times (default: 64, 1024 and '
+                             'the size clcachesrv uses).')
+    parser.add_argument('--algorithm', choices=sorted(HASHERS), action='append',
+                        help='Hash algorithm to measure; may be given several times (default: all available).')
+    parser.add_argument('--repeat', metavar='N', type=int, default=3, help='Runs to take the best of.')
+    args = parser.parse_args()
+
+    chunkSizes = sorted(set(size * 1024 for size in args.chunk_size or [64, 1024]) | {HashCache.CHUNK_SIZE})
+    readers = [('at once', readAtOnce)]
+    readers += [('chunks of {} KB'.format(size // 1024), readInChunks(size)) for size in chunkSizes]
+    readers += [('mmap', readMapped)]
+
+    algorithms = []
+    for name in args.algorithm or sorted(HASHERS):
+        try:
+            HASHERS[name]()
+            algorithms.append(name)
+        except ImportError as e:
+            print("Skipping {}: {}".format(name, e))
+
+    with tempfile.TemporaryDirectory() as directory:
+        datasets = [
+            ('headers', createDataset(directory, 'header', args.headers, 2 * 1024, 64 * 1024)),
+            ('large files', createDataset(directory, 'large', args.large_files, args.large_size * 1024 * 1024,
+                                          args.large_size * 1024 * 1024)),
+        ]
+        for _, paths in datasets:
+            for path in paths:
+                readAtOnce(path, HASHERS['md5']())
+
+        print("{:<12} {:<10} {:<18} {:>10}".format("dataset", "algorithm", "reading", "MB/s"))
+        for datasetName, paths in datasets:
+            for algorithm in algorithms:
+                for readerName, read in readers:
+                    throughput = measure(paths, HASHERS[algorithm], read, args.repeat)
+                    print("{:<12} {:<10} {:<18} {:>10.0f}".format(datasetName, algorithm, readerName, throughput))
+
+
+if __name__ == '__main__':
+    main()
@@ -20,15 +20,22 @@ import hashlib
 import json
 import multiprocessing
 import os
-import pickle
+import random
 import re
+import socket
+import sqlite3
+import ssl
 import subprocess
 import sys
 import threading
-from tempfile import TemporaryFile
+import time
+from tempfile import TemporaryDirectory, TemporaryFile
 from typing import Any, List, Tuple, Iterator, Dict
 from atomicwrites import atomic_write
 
+from clcache.server.protocol import COMMAND_MARKER, ERROR_MARKER, FIELD_SEPARATOR, PIPE_NAME, ErrorCode, ServerError, \
+    decodeError, encodeRequest, queryServer
+
 VERSION = "4.2.1-dev"
 
 HashAlgorithm = hashlib.md5
@@ -55,6 +62,21 @@ except ImportError:
 # For possible values see https://docs.python.org/2/library/codecs.html
 CACHE_COMPILER_OUTPUT_STORAGE_CODEC = 'utf-8'
 
+# NTSTATUS values a process which crashed exits with
+CRASH_CODES = {
+    0x80000003: 'STATUS_BREAKPOINT',
+    0xC0000005: 'STATUS_ACCESS_VIOLATION',
+    0xC0000017: 'STATUS_NO_MEMORY',
+    0xC000001D: 'STATUS_ILLEGAL_INSTRUCTION',
+    0xC0000094: 'STATUS_INTEGER_DIVIDE_BY_ZERO',
+    0xC00000FD: 'STATUS_STACK_OVERFLOW',
+    0xC0000135: 'STATUS_DLL_NOT_FOUND',
+    0xC0000142: 'STATUS_DLL_INIT_FAILED',
+    0xC000013A: 'STATUS_CONTROL_C_EXIT',
+    0xC0000374: 'STATUS_HEAP_CORRUPTION',
+    0xC0000409: 'STATUS_STACK_BUFFER_OVERRUN',
+}
+
 # The cl default codec
 CL_DEFAULT_CODEC = 'mbcs'
 
@@ -72,9 +94,15 @@ BASEDIR_REPLACEMENT = '?'
 # to use it as mark for relative path.
 BUILDDIR_REPLACEMENT = '*'
 
+# Strings, by which BASE_DIR and BUILD_DIR are replaced in cached compiler output
+# if CLCACHE_REMAP_OUTPUT is set. Output is free text, so they must be unlikely to
+# appear in it otherwise.
+OUTPUT_BASEDIR_PLACEHOLDER = '<CLCACHE_BASEDIR>'
+OUTPUT_BUILDDIR_PLACEHOLDER = '<CLCACHE_BUILDDIR>'
+
 # Define some Win32 API constants here to avoid dependency on win32pipe
-NMPWAIT_WAIT_FOREVER = wintypes.DWORD(0xFFFFFFFF)
 ERROR_PIPE_BUSY = 231
+DETACHED_PROCESS = 0x00000008
 
 # ManifestEntry: an entry in a manifest file
 # `includeFiles`: list of paths to include files, which this source file uses
@@ -82,7 +110,19 @@ ERROR_PIPE_BUSY = 231
 # `objectHash`: hash of the object in cache
 ManifestEntry = namedtuple('ManifestEntry', ['includeFiles', 'includesContentHash', 'objectHash'])
 
-CompilerArtifacts = namedtuple('CompilerArtifacts', ['objectFilePath', 'stdout', 'stderr'])
+# CompilerArtifacts: what a compile produced, which is stored in the cache
+# `sourceDependencies`: the file written for /sourceDependencies (with collapsed
+# paths, see collapseSourceDependencies()), or None if it was not requested
+CompilerArtifacts = namedtuple('CompilerArtifacts', ['objectFilePath', 'stdout', 'stderr', 'sourceDependencies'])
+# namedtuple() only takes defaults since Python 3.7
+CompilerArtifacts.__new__.__defaults__ = (None,)
+
+# SourceDependencies: what cl.exe reports for /sourceDependencies, see parseSourceDependencies()
+# `includes`: set of paths to the headers included
+# `modules`: dict of the names of the modules imported to the paths of their BMI (.ifc) files
+# `headerUnits`: dict of the paths to the header units imported to the paths of their BMI files
+# `pch`: path to the precompiled header file used, or None
+SourceDependencies = namedtuple('SourceDependencies', ['includes', 'modules', 'headerUnits', 'pch'])
 
 def printBinary(stream, rawData):
     with OUTPUT_LOCK:
@@ -149,6 +189,19 @@ if BASEDIR is None or not os.path.exists(BASEDIR):
                         BASEDIR = normalizeDir(value)
                     break
 
+def sourcePathMap():
+    """Returns the path written into objects instead of CLCACHE_BASEDIR, if any"""
+    return os.environ.get('CLCACHE_PATHMAP') if BASEDIR is not None else None
+
+
+def mapSourcePaths(cmdLine):
+    """Makes the compiler write sourcePathMap() instead of CLCACHE_BASEDIR into __FILE__ and debug information"""
+    target = sourcePathMap()
+    if target is None:
+        return cmdLine
+    return ['/pathmap:{}={}'.format(BASEDIR, target)] + cmdLine
+
+
 def getCachedCompilerConsoleOutput(path):
     try:
         with open(path, 'rb') as f:
@@ -164,6 +217,10 @@ class IncludeNotFoundException(Exception):
     pass
 
 
+class ToolchainUnknownException(Exception):
+    pass
+
+
 class CacheLockException(Exception):
     pass
 
@@ -315,6 +372,9 @@ class ManifestRepository:
                 commandLine.extend(["/" + k + arg for arg in arguments[k]])
 
         commandLine.extend(collapseBasedirInCmdPath(arg) for arg in inputFiles)
+        if sourcePathMap() is not None:
+            # Added by mapSourcePaths() when compiling, BASEDIR itself does not matter
+            commandLine.append('/pathmap:' + sourcePathMap())
 
         additionalData = "{}|{}|{}".format(
             compilerHash, commandLine, ManifestRepository.MANIFEST_FILE_FORMAT_VERSION)
@@ -393,6 +453,7 @@ class CompilerArtifactsSection:
     OBJECT_FILE = 'object'
     STDOUT_FILE = 'output.txt'
     STDERR_FILE = 'stderr.txt'
+    SOURCE_DEPENDENCIES_FILE = 'sourcedependencies.json'
 
     def __init__(self, compilerArtifactsSectionDir):
         self.compilerArtifactsSectionDir = compilerArtifactsSectionDir
@@ -426,6 +487,10 @@ class CompilerArtifactsSection:
         if artifacts.stderr != '':
             setCachedCompilerConsoleOutput(os.path.join(tempEntryDir, CompilerArtifactsSection.STDERR_FILE),
                                            artifacts.stderr)
+        if artifacts.sourceDependencies is not None:
+            setCachedCompilerConsoleOutput(
+                os.path.join(tempEntryDir, CompilerArtifactsSection.SOURCE_DEPENDENCIES_FILE),
+                artifacts.sourceDependencies)
         # Replace the full cache entry atomically
         os.replace(tempEntryDir, cacheEntryDir)
         return size
@@ -433,10 +498,12 @@ class CompilerArtifactsSection:
     def getEntry(self, key):
         assert self.hasEntry(key)
         cacheEntryDir = self.cacheEntryDir(key)
+        sourceDependenciesFile = os.path.join(cacheEntryDir, CompilerArtifactsSection.SOURCE_DEPENDENCIES_FILE)
         return CompilerArtifacts(
             os.path.join(cacheEntryDir, CompilerArtifactsSection.OBJECT_FILE),
             getCachedCompilerConsoleOutput(os.path.join(cacheEntryDir, CompilerArtifactsSection.STDOUT_FILE)),
-            getCachedCompilerConsoleOutput(os.path.join(cacheEntryDir, CompilerArtifactsSection.STDERR_FILE))
+            getCachedCompilerConsoleOutput(os.path.join(cacheEntryDir, CompilerArtifactsSection.STDERR_FILE)),
+            getCachedCompilerConsoleOutput(sourceDependenciesFile) if os.path.exists(sourceDependenciesFile) else None
             )
 
 
@@ -490,7 +557,7 @@ class CompilerArtifactsRepository:
     def computeKeyNodirect(compilerBinary, commandLine, environment):
         printTraceStatement("computeKeyNodirect")
 
-        ppcmd = ["/EP"] + [arg for arg in commandLine if arg not in ("-c", "/c")]
+        ppcmd = mapSourcePaths(["/EP"] + [arg for arg in commandLine if arg not in ("-c", "/c")])
 
         returnCode, preprocessedSourceCode, ppStderrBinary = \
             invokeRealCompiler(compilerBinary, ppcmd, captureOutput=True, outputAsString=False, environment=environment)
@@ -501,6 +568,8 @@ class CompilerArtifactsRepository:
 
         compilerHash = getCompilerHash(compilerBinary)
         normalizedCmdLine = CompilerArtifactsRepository._normalizedCommandLine(commandLine)
+        if sourcePathMap() is not None:
+            normalizedCmdLine.append('/pathmap:' + sourcePathMap())
 
         # preprocessedSourceCode = substituteDirPlaceholder(preprocessedSourceCode)
 
@@ -508,6 +577,16 @@ class CompilerArtifactsRepository:
         h.update(compilerHash.encode("UTF-8"))
         h.update(' '.join(normalizedCmdLine).encode("UTF-8"))
         h.update(preprocessedSourceCode)
+        pchFile = CommandLineAnalyzer.precompiledHeaderFileOf(commandLine)
+        if pchFile is not None:
+            h.update(getFileHash(pchFile).encode("UTF-8"))
+        # Neither are the interfaces of the modules and header units imported
+        for ifcFile in CommandLineAnalyzer.referencedIfcFilesOf(commandLine):
+            h.update(getFileHash(ifcFile).encode("UTF-8"))
+        # Entries stored before the /sourceDependencies file was stored with them lack it
+        options, _ = CommandLineAnalyzer.parseArgumentsAndInputFiles(commandLine)
+        if options.get('sourceDependencies'):
+            h.update(b'sourceDependencies')
         return h.hexdigest()
 
     @staticmethod
@@ -554,6 +633,8 @@ class CacheFileStrategy:
 
         self.configuration = Configuration(os.path.join(self.dir, "config.txt"))
         self.statistics = Statistics(os.path.join(self.dir, "stats.txt"))
+        self.showIncludesPrefixesFile = os.path.join(self.dir, "showincludes.txt")
+        self.history = History(os.path.join(self.dir, "history.sqlite"))
 
     def __str__(self):
         return "Disk cache at {}".format(self.dir)
@@ -654,6 +735,14 @@ class Cache:
     def statistics(self):
         return self.strategy.statistics
 
+    @property
+    def showIncludesPrefixesFile(self):
+        return self.strategy.showIncludesPrefixesFile
+
+    @property
+    def history(self):
+        return self.strategy.history
+
     def clean(self, stats, maximumSize):
         return self.strategy.clean(stats, maximumSize)
 
@@ -710,6 +799,106 @@ class PersistentJSONDict:
         return type(self) is type(other) and self.__dict__ == other.__dict__
 
 
+class History:
+    """Outcome of each compile, kept in an SQLite database if CLCACHE_HISTORY is set
+
+    Misses are recorded with the reason why the cache could not be used. Compiles
+    which cannot be cached at all count as misses, too, without an object file.
+    """
+    HIT = 'hit'
+    MISS = 'miss'
+
+    # Reasons for misses. Where no entry is found for the source file and its flags,
+    # the flags the object file was compiled with last tell whether they changed.
+    NEW_SOURCE = 'new source'
+    SOURCE_CHANGED = 'source changed'
+    HEADER_CHANGED = 'header changed'
+    FLAGS_CHANGED = 'flags changed'
+    UNCACHEABLE = 'uncacheable flag'
+    EVICTED = 'cache evicted'
+    # Seconds to wait for other clcache processes writing to the database
+    TIMEOUT = 10
+
+    def __init__(self, databaseFile):
+        self._databaseFile = databaseFile
+
+    def _connect(self):
+        connection = sqlite3.connect(self._databaseFile, timeout=History.TIMEOUT)
+        connection.execute('PRAGMA journal_mode=WAL')
+        connection.execute('CREATE TABLE IF NOT EXISTS compiles (time REAL NOT NULL, objectFile TEXT NOT NULL, '
+                           'outcome TEXT NOT NULL, size INTEGER NOT NULL, reason TEXT, flags TEXT)')
+        columns = [row[1] for row in connection.execute('PRAGMA table_info(compiles)')]
+        if 'reason' not in columns:
+            # Written by a clcache version which did not record reasons yet
+            with connection:
+                connection.execute('ALTER TABLE compiles ADD COLUMN reason TEXT')
+                connection.execute('ALTER TABLE compiles ADD COLUMN flags TEXT')
+        return contextlib.closing(connection)
+
+    @staticmethod
+    def flagsOf(cmdLine):
+        # Left out since clcache adds it itself when it looks for the includes
+        return getStringHash('\n'.join(arg for arg in cmdLine if arg != '/showIncludes'))
+
+    def record(self, objectFile, outcome, size=0, reason=None, cmdLine=None):
+        """Records a cache hit or miss for objectFile; size is the number of bytes added to the cache"""
+        if 'CLCACHE_HISTORY' not in os.environ:
+            return
+        flags = History.flagsOf(cmdLine) if cmdLine is not None else None
+        try:
+            with self._connect() as connection, connection:
+                connection.execute('INSERT INTO compiles VALUES (?, ?, ?, ?, ?, ?)',
+                                   (time.time(), os.path.normcase(os.path.abspath(objectFile)) if objectFile else '',
+                                    outcome, size, reason, flags))
+        except sqlite3.Error as e:
+            # Not worth failing the compile for
+            printTraceStatement("Cannot record compile in {}: {}".format(self._databaseFile, e))
+
+    def newKeyMissReason(self, objectFile, cmdLine):
+        """Tells why there is no cache entry for compiling objectFile with cmdLine, None if not recording"""
+        if 'CLCACHE_HISTORY' not in os.environ:
+            return None
+        try:
+            with self._connect() as connection:
+                row = connection.execute(
+                    'SELECT flags FROM compiles WHERE objectFile = ? AND flags IS NOT NULL ORDER BY time DESC LIMIT 1',
+                    (os.path.normcase(os.path.abspath(objectFile)),)).fetchone()
+        except sqlite3.Error as e:
+            printTraceStatement("Cannot read the history of {}: {}".format(objectFile, e))
+            return None
+        if row is None:
+            return History.NEW_SOURCE
+        return History.SOURCE_CHANGED if row[0] == History.flagsOf(cmdLine) else History.FLAGS_CHANGED
+
+    def daily(self, days):
+        """Returns (day, hits, misses, bytes added) for each day of the last days days with compiles"""
+        with self._connect() as connection:
+            return connection.execute(
+                "SELECT date(time, 'unixepoch', 'localtime') AS day, SUM(outcome = ?), SUM(outcome = ?), SUM(size) "
+                "FROM compiles WHERE time >= ? GROUP BY day ORDER BY day",
+                (History.HIT, History.MISS, time.time() - days * 86400)).fetchall()
+
+    def topMisses(self, days, limit=10):
+        """Returns (object file, misses) for the object files missed most often in the last days days"""
+        with self._connect() as connection:
+            return connection.execute(
+                "SELECT objectFile, COUNT(*) AS misses FROM compiles WHERE time >= ? AND outcome = ? "
+                "AND objectFile != '' GROUP BY objectFile ORDER BY misses DESC, objectFile LIMIT ?",
+                (time.time() - days * 86400, History.MISS, limit)).fetchall()
+
+    def missReasons(self, days):
+        """Returns (reason, misses) for the reasons of the misses in the last days days, most frequent first"""
+        with self._connect() as connection:
+            return connection.execute(
+                "SELECT COALESCE(reason, 'unknown') AS why, COUNT(*) AS misses FROM compiles "
+                "WHERE time >= ? AND outcome = ? GROUP BY why ORDER BY misses DESC, why",
+                (time.time() - days * 86400, History.MISS)).fetchall()
+
+    def clear(self):
+        with self._connect() as connection, connection:
+            connection.execute('DELETE FROM compiles')
+
+
 class Configuration:
     _defaultValues = {"MaximumCacheSize": 40737418240} # 40 GiB
 
@@ -743,6 +932,7 @@ class Statistics:
     CALLS_FOR_LINKING = "CallsForLinking"
     CALLS_FOR_EXTERNAL_DEBUG_INFO = "CallsForExternalDebugInfo"
     CALLS_FOR_PREPROCESSING = "CallsForPreprocessing"
+    CALLS_WITH_MODULES = "CallsWithModules"
     CACHE_HITS = "CacheHits"
     CACHE_MISSES = "CacheMisses"
     EVICTED_MISSES = "EvictedMisses"
@@ -759,6 +949,7 @@ class Statistics:
         CALLS_FOR_LINKING,
         CALLS_FOR_EXTERNAL_DEBUG_INFO,
         CALLS_FOR_PREPROCESSING,
+        CALLS_WITH_MODULES,
         CACHE_HITS,
         CACHE_MISSES,
         EVICTED_MISSES,
@@ -813,6 +1004,12 @@ class Statistics:
     def registerCallWithPch(self):
         self._stats[Statistics.CALLS_WITH_PCH] += 1
 
+    def numCallsWithModules(self):
+        return self._stats[Statistics.CALLS_WITH_MODULES]
+
+    def registerCallWithModules(self):
+        self._stats[Statistics.CALLS_WITH_MODULES] += 1
+
     def numCallsForLinking(self):
         return self._stats[Statistics.CALLS_FOR_LINKING]
 
@@ -913,6 +1110,10 @@ class ExternalDebugInfoError(AnalysisError):
     pass
 
 
+class CalledWithModulesError(AnalysisError):
+    pass
+
+
 class CalledForPreprocessingError(AnalysisError):
     pass
 
@@ -922,37 +1123,199 @@ class InvalidArgumentError(AnalysisError):
 
 
 def getCompilerHash(compilerBinary):
-    stat = os.stat(compilerBinary)
-    data = '|'.join([
-        str(stat.st_mtime),
-        str(stat.st_size),
-        VERSION,
-        ])
+    toolchainDigest = getToolchainDigest(compilerBinary)
+    if toolchainDigest is not None:
+        # Unlike the time stamp of the compiler, the same on every machine
+        data = '|'.join([toolchainDigest, VERSION])
+    else:
+        stat = os.stat(compilerBinary)
+        data = '|'.join([
+            str(stat.st_mtime),
+            str(stat.st_size),
+            VERSION,
+            ])
     hasher = HashAlgorithm()
     hasher.update(data.encode("UTF-8"))
     return hasher.hexdigest()
 
 
+knownToolchainDigests: Dict[str, Any] = dict()
+def getToolchainDigest(compilerBinary):
+    """Returns the fingerprint clcachesrv takes of the compiler installation and environment, None if there is none
+
+    None means that the server cannot take fingerprints (e.g. because it is too
+    old), which does not change from one compile to the next. If the server does
+    not answer (in time), ToolchainUnknownException is raised instead: keys
+    made without the fingerprint would differ from those made with it.
+    """
+    if 'CLCACHE_SERVER' not in os.environ or 'CLCACHE_SERVER_ADDRESS' in os.environ:
+        return None
+    if compilerBinary in knownToolchainDigests:
+        return knownToolchainDigests[compilerBinary]
+    environment = ['{}={}'.format(name, os.environ.get(name, '')) for name in ('INCLUDE', 'LIB')]
+    try:
+        response = requestOverPipe(encodeRequest([COMMAND_MARKER + 'toolchain ' + os.path.abspath(compilerBinary)]
+                                                 + environment))
+    except OSError as e:
+        printTraceStatement("clcachesrv could not fingerprint {}: {}".format(compilerBinary, e))
+        raise ToolchainUnknownException(compilerBinary)
+    if response.startswith(ERROR_MARKER):
+        error = decodeError(response[:-1])
+        printTraceStatement("clcachesrv could not fingerprint {}: {}".format(compilerBinary, error))
+        if error.code != ErrorCode.BAD_REQUEST:
+            raise ToolchainUnknownException(compilerBinary)
+        digest = None
+    else:
+        digest = json.loads(response[:-1].decode('utf-8'))['digest']
+    knownToolchainDigests[compilerBinary] = digest
+    return digest
+
+
+# Connecting to a busy clcachesrv is retried after a delay doubling from
+# SERVER_BACKOFF_INITIAL up to SERVER_BACKOFF_MAX seconds, for up to
+# SERVER_CONNECT_TIMEOUT seconds in total
+SERVER_BACKOFF_INITIAL = 0.01
+SERVER_BACKOFF_MAX = 1.0
+SERVER_CONNECT_TIMEOUT = 30.0
+
+
+def serverBackoff(attempt):
+    """Seconds to wait before connecting to a busy server again
+
+    The delay is randomized, so parallel compiles do not retry in lockstep.
+    """
+    delay = min(SERVER_BACKOFF_MAX, SERVER_BACKOFF_INITIAL * 2 ** attempt)
+    return random.uniform(delay / 2, delay)
+
+
+def serverCommandLine():
+    """Returns the command line clcachesrv is started again with, None if it is not known
+
+    CLCACHE_SERVER_COMMAND gives it, options included; otherwise the server is
+    started with default options, from clcachesrv.exe next to a frozen clcache.
+    """
+    if 'CLCACHE_SERVER_COMMAND' in os.environ:
+        return splitCommandsFile(os.environ['CLCACHE_SERVER_COMMAND'].strip()) or None
+    if getattr(sys, 'frozen', False):
+        serverBinary = os.path.join(os.path.dirname(sys.executable), 'clcachesrv.exe')
+        return [serverBinary] if os.path.isfile(serverBinary) else None
+    return [sys.executable, '-m', 'clcache.server']
+
+
+def startServer():
+    commandLine = serverCommandLine()
+    if commandLine is None:
+        printTraceStatement("clcachesrv went away, not knowing how to start it again")
+        return
+    printTraceStatement("clcachesrv went away, starting it again")
+    subprocess.Popen(commandLine, stdin=subprocess.DEVNULL, stdout=subprocess.DEVNULL,
+                     stderr=subprocess.DEVNULL, creationflags=DETACHED_PROCESS)
+
+
+def requestOverPipe(request):
+    deadline = time.monotonic() + SERVER_CONNECT_TIMEOUT
+    attempt = 0
+    restarted = False
+    while True:
+        try:
+            with open(PIPE_NAME, 'w+b') as f:
+                f.write(request)
+                return f.read()
+        except FileNotFoundError:
+            # A server which is not running at all is not started; one which went
+            # away while clcache waited for it (e.g. crashed) is, but only once
+            if attempt == 0:
+                raise
+            if not restarted:
+                startServer()
+                restarted = True
+        except OSError as e:
+            if e.errno != errno.EINVAL or windll.kernel32.GetLastError() != ERROR_PIPE_BUSY:
+                raise
+
+        remaining = deadline - time.monotonic()
+        if remaining <= 0:
+            raise TimeoutError("clcachesrv did not accept a connection within {:g} seconds"
+                               .format(SERVER_CONNECT_TIMEOUT))
+        time.sleep(min(serverBackoff(attempt), remaining))
+        attempt += 1
+
+
+def requestOverTls(address, request):
+    host, _, port = address.rpartition(':')
+    context = ssl.create_default_context(cafile=os.environ.get('CLCACHE_SERVER_CA'))
+    if 'CLCACHE_SERVER_CERT' in os.environ:
+        context.load_cert_chain(os.environ['CLCACHE_SERVER_CERT'])
+    with socket.create_connection((host.strip('[]'), int(port))) as sock:
+        with context.wrap_socket(sock, server_hostname=host.strip('[]')) as tlsSock:
+            tlsSock.sendall(request)
+            response = bytearray()
+            while not response.endswith(b'\x00'):
+                chunk = tlsSock.recv(65536)
+                if not chunk:
+                    break
+                response += chunk
+            return bytes(response)
+
+
+# Path lists longer than this are split into chunks hashed by the server
+# in parallel, over up to SERVER_CONNECTIONS connections
+SERVER_CHUNK_SIZE = 2000
+SERVER_CONNECTIONS = 4
+
+
 def getFileHashes(filePaths):
-    if 'CLCACHE_SERVER' in os.environ:
-        pipeName = r'\\.\pipe\clcache_srv'
-        while True:
-            try:
-                with open(pipeName, 'w+b') as f:
-                    f.write('\n'.join(filePaths).encode('utf-8'))
-                    f.write(b'\x00')
-                    response = f.read()
-                    if response.startswith(b'!'):
-                        raise pickle.loads(response[1:-1])
-                    return response[:-1].decode('utf-8').splitlines()
-            except OSError as e:
-                if e.errno == errno.EINVAL and windll.kernel32.GetLastError() == ERROR_PIPE_BUSY:
-                    windll.kernel32.WaitNamedPipeW(pipeName, NMPWAIT_WAIT_FOREVER)
-                else:
-                    raise
+    if 'CLCACHE_SERVER' in os.environ or 'CLCACHE_SERVER_ADDRESS' in os.environ:
+        header = {'cwd': os.getcwd()}
+        if 'CLCACHE_SERVER_NAMESPACE' in os.environ:
+            header['namespace'] = os.environ['CLCACHE_SERVER_NAMESPACE']
+        if 'CLCACHE_SERVER_CHECK_PATHS' in os.environ:
+            header['canonical'] = True
+        if len(filePaths) <= SERVER_CHUNK_SIZE:
+            return requestFileHashes(filePaths, header)
+
+        chunks = [filePaths[i:i + SERVER_CHUNK_SIZE] for i in range(0, len(filePaths), SERVER_CHUNK_SIZE)]
+        with concurrent.futures.ThreadPoolExecutor(max_workers=SERVER_CONNECTIONS) as executor:
+            return [fileHash for fileHashes in executor.map(lambda chunk: requestFileHashes(chunk, header), chunks)
+                    for fileHash in fileHashes]
     else:
         return [getFileHashCached(filePath) for filePath in filePaths]
 
+
+def requestFileHashes(filePaths, header):
+    request = encodeRequest(filePaths, header)
+    if 'CLCACHE_SERVER_ADDRESS' in os.environ:
+        response = requestOverTls(os.environ['CLCACHE_SERVER_ADDRESS'], request)
+    else:
+        try:
+            response = requestOverPipe(request)
+        except TimeoutError as e:
+            printTraceStatement("{}, hashing files locally".format(e))
+            return [getFileHashCached(filePath) for filePath in filePaths]
+
+    if response.startswith(ERROR_MARKER):
+        error = decodeError(response[:-1])
+        if error.code in ErrorCode.FILE_ERRORS:
+            raise error.toOSError()
+        # The server could not serve the request (e.g. timed
+        # out), but the files themselves are probably fine
+        printTraceStatement("clcachesrv failed ({}), hashing files locally".format(error))
+        return [getFileHashCached(filePath) for filePath in filePaths]
+    hashes = response[:-1].decode('utf-8').splitlines()
+    if header.get('canonical'):
+        return [checkCanonicalPath(filePath, record) for filePath, record in zip(filePaths, hashes)]
+    return hashes
+
+
+def checkCanonicalPath(filePath, record):
+    """Returns the hashsum of a server response record, or the local one if the server hashed another file"""
+    hashsum, _, serverPath = record.partition(FIELD_SEPARATOR)
+    localPath = os.path.realpath(filePath)
+    if os.path.normcase(serverPath) != os.path.normcase(localPath):
+        printTraceStatement("clcachesrv hashed {} instead of {}, hashing it locally".format(serverPath, localPath))
+        return getFileHashCached(filePath)
+    return hashsum
+
 knownHashes: Dict[str, str] = dict()
 def getFileHashCached(filePath):
     if filePath in knownHashes:
@@ -1163,6 +1526,11 @@ class CommandLineTokenizer:
 
     def _quotedState(self, currentChar):
         if currentChar == '"':
+            # Two consecutive quotes within a quoted argument yield a literal one
+            if self._content[self._pos + 1:self._pos + 2] == '"':
+                self._token += '"'
+                self._pos += 1
+                return self._quotedState
             return self._unquotedState
 
         if currentChar == '\\':
@@ -1198,7 +1566,7 @@ def expandCommandLine(cmdline):
     ret = []
 
     for arg in cmdline:
-        if arg[0] == '@':
+        if arg.startswith('@'):
             includeFile = arg[1:]
             with open(includeFile, 'rb') as f:
                 rawBytes = f.read()
@@ -1206,6 +1574,7 @@ def expandCommandLine(cmdline):
             encoding = None
 
             bomToEncoding = {
+                codecs.BOM_UTF8: 'utf-8',
                 codecs.BOM_UTF32_BE: 'utf-32-be',
                 codecs.BOM_UTF32_LE: 'utf-32-le',
                 codecs.BOM_UTF16_BE: 'utf-16-be',
@@ -1230,6 +1599,11 @@ def expandCommandLine(cmdline):
     return ret
 
 
+def embedDebugInfo(cmdLine):
+    """Replaces /Zi and /ZI, which write debug information to a PDB file, by /Z7, which embeds it in the object"""
+    return ['/Z7' if arg[1:] in ('Zi', 'ZI') and arg[:1] in ('/', '-') else arg for arg in cmdLine]
+
+
 def extendCommandLineFromEnvironment(cmdLine, environment):
     remainingEnvironment = environment.copy()
 
@@ -1306,6 +1680,9 @@ class CommandLineAnalyzer:
         ArgumentT3('external:I'), ArgumentT3('external:env'),
         # /NAME parameter
         ArgumentT4("Xclang"),
+        ArgumentT4('sourceDependencies'), ArgumentT4('sourceDependencies:directives'),
+        ArgumentT4('reference'), ArgumentT4('headerUnit'), ArgumentT4('headerUnit:quote'),
+        ArgumentT4('headerUnit:angle'), ArgumentT4('ifcOutput'), ArgumentT4('ifcSearchDir'),
     }
     argumentsWithParameterSorted = sorted(argumentsWithParameter, key=len, reverse=True)
 
@@ -1363,6 +1740,54 @@ class CommandLineAnalyzer:
 
         return dict(arguments), inputFiles
 
+    @staticmethod
+    def precompiledHeaderFile(options):
+        """Returns the path of the precompiled header file a /Yu compile reads, or None if it cannot be told"""
+        if 'Yu' not in options or 'Y-' in options:
+            return None
+        if options.get('Fp'):
+            pchFile = options['Fp'][-1]
+            if pchFile.endswith(('\\', '/')):
+                # cl.exe picks a name depending on its version then
+                return None
+        elif options['Yu'][-1]:
+            pchFile = basenameWithoutExtension(options['Yu'][-1])
+        else:
+            return None
+        if not os.path.splitext(pchFile)[1]:
+            pchFile += '.pch'
+        return os.path.normpath(pchFile)
+
+    @staticmethod
+    def precompiledHeaderFileOf(cmdline: List[str]):
+        options, _ = CommandLineAnalyzer.parseArgumentsAndInputFiles(cmdline)
+        return CommandLineAnalyzer.precompiledHeaderFile(options)
+
+    @staticmethod
+    def referencedIfcFiles(options):
+        """Returns the paths of the module and header unit interfaces (.ifc) named by /reference and /headerUnit"""
+        ifcFiles = []
+        for option in ('reference', 'headerUnit', 'headerUnit:quote', 'headerUnit:angle'):
+            # Either a file name or NAME=FILE
+            ifcFiles += [os.path.normpath(value.partition('=')[2] or value) for value in options.get(option, [])]
+        return ifcFiles
+
+    @staticmethod
+    def referencedIfcFilesOf(cmdline: List[str]):
+        options, _ = CommandLineAnalyzer.parseArgumentsAndInputFiles(cmdline)
+        return CommandLineAnalyzer.referencedIfcFiles(options)
+
+    @staticmethod
+    def sourceDependenciesFileOf(cmdline: List[str], sourceFile):
+        """Returns the path of the JSON file /sourceDependencies makes cl.exe write for sourceFile, or None"""
+        options, _ = CommandLineAnalyzer.parseArgumentsAndInputFiles(cmdline)
+        if not options.get('sourceDependencies'):
+            return None
+        target = options['sourceDependencies'][-1]
+        if os.path.isdir(target):
+            target = os.path.join(target, os.path.basename(sourceFile) + '.json')
+        return os.path.normpath(target)
+
     @staticmethod
     def analyze(cmdline: List[str]) -> Tuple[List[Tuple[str, str]], List[str]]:
         options, inputFiles = CommandLineAnalyzer.parseArgumentsAndInputFiles(cmdline)
@@ -1388,11 +1813,31 @@ class CommandLineAnalyzer:
 
         # Technically, it would be possible to support /Zi: we'd just need to
         # copy the generated .pdb files into/out of the cache.
-        if 'Zi' in options:
+        if 'Zi' in options or 'ZI' in options:
             raise ExternalDebugInfoError()
 
-        if 'Yc' in options or 'Yu' in options:
-            raise CalledWithPchError()
+        if 'Y-' not in options:
+            if 'Yc' in options:
+                raise CalledWithPchError("creating a precompiled header (/Yc)")
+            if 'Yu' in options:
+                pchFile = CommandLineAnalyzer.precompiledHeaderFile(options)
+                if pchFile is None:
+                    raise CalledWithPchError("cannot tell the name of the precompiled header file")
+                if not os.path.isfile(pchFile):
+                    raise CalledWithPchError("precompiled header file {} does not exist".format(pchFile))
+
+        # The interfaces compiles produce are not cached, and those looked up in
+        # directories cannot be told; only interfaces named explicitly are hashed
+        for option in ('interface', 'internalPartition', 'exportHeader', 'ifcOutput'):
+            if option in options:
+                raise CalledWithModulesError("producing a module interface (/{})".format(option))
+        if any(os.path.splitext(inputFile)[1].lower() in ('.ixx', '.cppm') for inputFile, _ in inputFiles):
+            raise CalledWithModulesError("compiling a module interface unit")
+        if 'ifcSearchDir' in options:
+            raise CalledWithModulesError("looking up module interfaces in directories (/ifcSearchDir)")
+        for ifcFile in CommandLineAnalyzer.referencedIfcFiles(options):
+            if not os.path.isfile(ifcFile):
+                raise CalledWithModulesError("module interface {} does not exist".format(ifcFile))
 
         if 'link' in options or 'c' not in options:
             raise CalledForLinkError()
@@ -1418,8 +1863,12 @@ class CommandLineAnalyzer:
         return inputFiles, objectFiles
 
 
-def invokeRealCompiler(compilerBinary, cmdLine, captureOutput=False, outputAsString=True, environment=None):
+def invokeRealCompiler(compilerBinary, cmdLine, captureOutput=False, outputAsString=True, environment=None,
+                       distribute=False):
     realCmdline = [compilerBinary] + cmdLine
+    if distribute and os.environ.get('CLCACHE_PREFIX'):
+        # E.g. the wrapper of a distributed build system; the object it produces is added to the cache as usual
+        realCmdline = splitCommandsFile(os.environ['CLCACHE_PREFIX']) + realCmdline
     printTraceStatement("Invoking real compiler as {}".format(realCmdline))
 
     environment = environment or os.environ
@@ -1446,6 +1895,8 @@ def invokeRealCompiler(compilerBinary, cmdLine, captureOutput=False, outputAsStr
         returnCode = subprocess.call(realCmdline, env=environment)
 
     printTraceStatement("Real compiler returned code {0:d}".format(returnCode))
+    if isCrashCode(returnCode):
+        reportCompilerCrash(returnCode, realCmdline)
 
     if outputAsString:
         stdoutString = stdout.decode(CL_DEFAULT_CODEC)
@@ -1454,6 +1905,42 @@ def invokeRealCompiler(compilerBinary, cmdLine, captureOutput=False, outputAsStr
 
     return returnCode, stdout, stderr
 
+def isCrashCode(exitCode):
+    # Error severity NTSTATUS values have the two highest bits set, warnings (like breakpoints) just the highest one
+    exitCode &= 0xFFFFFFFF
+    return exitCode in CRASH_CODES or exitCode & 0xC0000000 == 0xC0000000
+
+
+def describeExitCode(exitCode):
+    exitCode &= 0xFFFFFFFF
+    return "{} (0x{:08X})".format(CRASH_CODES.get(exitCode, "exception"), exitCode)
+
+
+def reportCompilerCrash(exitCode, cmdLine):
+    description = describeExitCode(exitCode)
+    printErrStr("clcache: real compiler terminated with {}".format(description))
+    markerFile = os.environ.get("CLCACHE_CRASH_MARKER")
+    if markerFile:
+        # One JSON record per line, so concurrent compiles can append to the same file
+        record = {'exitCode': exitCode & 0xFFFFFFFF, 'description': description, 'commandLine': cmdLine}
+        try:
+            with open(markerFile, 'a') as f:
+                f.write(json.dumps(record) + '\n')
+        except OSError as e:
+            printErrStr("clcache: failed to write crash marker {}: {}".format(markerFile, e))
+
+
+def processExitCode(exitCode):
+    """Returns exitCode such that sys.exit() passes all of its 32 bits on to the parent process
+
+    Exit codes are unsigned, e.g. 0xC0000005 for an access violation. Python
+    versions before 3.12.8 converted larger values than a (32 bit) C long can
+    hold to -1, so pass them on as the negative number with the same bits.
+    """
+    exitCode &= 0xFFFFFFFF
+    return exitCode - (1 << 32) if exitCode > 0x7FFFFFFF else exitCode
+
+
 # Returns the amount of jobs which should be run in parallel when
 # invoked in batch mode as determined by the /MP argument
 def jobCount(cmdLine):
@@ -1475,6 +1962,41 @@ def jobCount(cmdLine):
         # not expected to happen
         return 2
 
+def printServerReport():
+    template = """
+clcachesrv savings:
+  bytes not re-hashed       : {:,} bytes
+  bytes hashed from disk    : {:,} bytes
+  hashing throughput        : {:.1f} MB/s
+  estimated time saved      : {:.1f} s""".strip()
+
+    try:
+        response = queryServer('stats')
+    except (OSError, ServerError) as e:
+        print("Failed to query clcachesrv: {}".format(e), file=sys.stderr)
+        return 1
+    if response is None:
+        print("clcachesrv is not running", file=sys.stderr)
+        return 1
+
+    stats = json.loads(response.decode('utf-8'))
+    hashedBytes = stats.get('totalColdHashBytes', 0)
+    hashingSeconds = stats.get('totalColdHashSeconds', 0)
+    print(template.format(
+        stats.get('totalBytesNotRehashed', 0),
+        hashedBytes,
+        hashedBytes / hashingSeconds / (1024 * 1024) if hashingSeconds else 0,
+        stats.get('estimatedSecondsSaved', 0),
+    ))
+    clients = stats.get('clients', {})
+    if clients:
+        print("clcachesrv load by client:")
+        for name, counts in sorted(clients.items(), key=lambda item: -item[1].get('paths', 0)):
+            print("  {:<25} : {:,} requests, {:,} paths".format(name, counts.get('requests', 0),
+                                                                counts.get('paths', 0)))
+    return 0
+
+
 def printStatistics(cache):
     template = """
 clcache statistics:
@@ -1495,7 +2017,8 @@ clcache statistics:
     called for external debug  : {}
     called w/o source          : {}
     called w/ multiple sources : {}
-    called w/ PCH              : {}""".strip()
+    called w/ PCH              : {}
+    called w/ modules          : {}""".strip()
 
     with cache.statistics.lock, cache.statistics as stats, cache.configuration as cfg:
         print(template.format(
@@ -1515,12 +2038,43 @@ clcache statistics:
             stats.numCallsWithoutSourceFile(),
             stats.numCallsWithMultipleSourceFiles(),
             stats.numCallsWithPch(),
+            stats.numCallsWithModules(),
         ))
 
 
+def printHistory(cache, days):
+    try:
+        daily = cache.history.daily(days)
+        topMisses = cache.history.topMisses(days)
+        missReasons = cache.history.missReasons(days)
+    except sqlite3.Error as e:
+        print("Failed to read the history of compiles: {}".format(e), file=sys.stderr)
+        return 1
+
+    print("clcache history of the last {} days:".format(days))
+    print("  {:<12} {:>8} {:>8} {:>9} {:>16}".format("day", "hits", "misses", "hit rate", "bytes added"))
+    for day, hits, misses, bytesAdded in daily:
+        print("  {:<12} {:>8,} {:>8,} {:>8.1f}% {:>16,}".format(day, hits, misses, 100.0 * hits / (hits + misses),
+                                                                bytesAdded))
+    if missReasons:
+        totalMisses = sum(misses for _, misses in missReasons)
+        print("misses by reason:")
+        for reason, misses in missReasons:
+            print("  {:<18} {:>8,} {:>8.1f}%".format(reason, misses, 100.0 * misses / totalMisses))
+    if topMisses:
+        print("object files missed most often:")
+        for objectFile, misses in topMisses:
+            print("  {:>8,}  {}".format(misses, objectFile))
+    return 0
+
+
 def resetStatistics(cache):
     with cache.statistics.lock, cache.statistics as stats:
         stats.resetCounters()
+    try:
+        cache.history.clear()
+    except sqlite3.Error as e:
+        printTraceStatement("Cannot clear the history of compiles: {}".format(e))
 
 
 def cleanCache(cache):
@@ -1533,50 +2087,143 @@ def clearCache(cache):
         cache.clean(stats, 0)
 
 
+# Name of the header included by the source file compiled to learn how the
+# compiler words /showIncludes lines, see detectShowIncludesPrefix()
+SHOW_INCLUDES_PROBE = 'clcache_probe.h'
+
+
+def parseShowIncludesPrefix(compilerOutput, headerName):
+    """Returns the text preceding the path of headerName in /showIncludes output, or None"""
+    reProbe = re.compile(r'^(?P<prefix>\S.*?)\s+[A-Za-z]:\\.*\\' + re.escape(headerName) + r'$', re.IGNORECASE)
+    for line in compilerOutput.splitlines():
+        match = reProbe.match(line.rstrip())
+        if match is not None:
+            return match.group('prefix')
+    return None
+
+
+def detectShowIncludesPrefix(compiler):
+    with TemporaryDirectory() as probeDir:
+        with open(os.path.join(probeDir, SHOW_INCLUDES_PROBE), 'w'):
+            pass
+        sourceFile = os.path.join(probeDir, 'clcache_probe.c')
+        with open(sourceFile, 'w') as f:
+            f.write('#include "{}"\n'.format(SHOW_INCLUDES_PROBE))
+        _, compilerOutput, _ = invokeRealCompiler(compiler, ['/nologo', '/showIncludes', '/Zs', sourceFile],
+                                                  captureOutput=True)
+    return parseShowIncludesPrefix(compilerOutput, SHOW_INCLUDES_PROBE)
+
+
+def getShowIncludesPrefix(cache, compiler):
+    """Returns the /showIncludes prefix of the compiler's locale, probing each toolchain once per locale
+
+    Besides the installed language packs, VSLANG and the console's OEM code
+    page decide how the compiler words its messages.
+    """
+    key = '{}:{}:{}'.format(getCompilerHash(compiler), os.environ.get('VSLANG', ''), windll.kernel32.GetOEMCP())
+    prefixes = PersistentJSONDict(cache.showIncludesPrefixesFile)
+    if key not in prefixes:
+        # A failed probe is remembered too, includes are then found by parseIncludesSet's heuristic
+        prefixes[key] = detectShowIncludesPrefix(compiler)
+        printTraceStatement("/showIncludes prefix of {}: {!r}".format(compiler, prefixes[key]))
+        prefixes.save()
+    return prefixes[key]
+
+
 # Returns pair:
 #   1. set of include filepaths
 #   2. new compiler output
 # Output changes if strip is True in that case all lines with include
 # directives are stripped from it
-def parseIncludesSet(compilerOutput, sourceFile, strip):
+def parseIncludesSet(compilerOutput, sourceFile, strip, prefix=None):
     newOutput = []
     includesSet = set()
 
-    # Example lines
-    # Note: including file:         C:\Program Files (x86)\Microsoft Visual Studio 12.0\VC\INCLUDE\limits.h
-    # Hinweis: Einlesen der Datei:   C:\Program Files (x86)\Microsoft Visual Studio 12.0\VC\INCLUDE\iterator
-    #
-    # So we match
-    # - one word (translation of "note")
-    # - colon
-    # - space
-    # - a phrase containing characters and spaces (translation of "including file")
-    # - colon
-    # - one or more spaces
-    # - the file path, starting with a non-whitespace character
-    reFilePath = re.compile(r'^(\w+): ([ \w]+):( +)(?P<file_path>\S.*)$')
+    if prefix:
+        # The compiler's own wording, see getShowIncludesPrefix()
+        reFilePath = re.compile(r'^' + re.escape(prefix) + r'\s+(?P<file_path>\S.*)$')
+    else:
+        # Example lines
+        # Note: including file:         C:\Program Files (x86)\Microsoft Visual Studio 12.0\VC\INCLUDE\limits.h
+        # Hinweis: Einlesen der Datei:   C:\Program Files (x86)\Microsoft Visual Studio 12.0\VC\INCLUDE\iterator
+        # Remarque : inclusion du fichier :  C:\Program Files (x86)\Microsoft Visual Studio 12.0\VC\INCLUDE\limits.h
+        #
+        # So we match
+        # - one word (translation of "note")
+        # - optional (possibly non-breaking) space, colon
+        # - space
+        # - a phrase containing characters and spaces (translation of "including file")
+        # - optional (possibly non-breaking) space, colon
+        # - one or more spaces
+        # - the file path, starting with a non-whitespace character
+        reFilePath = re.compile(r'^(\w+)\s?: ([ \w]+?)\s?:( +)(?P<file_path>\S.*)$')
 
     absSourceFile = os.path.normcase(os.path.abspath(sourceFile))
+    matched = False
     for line in compilerOutput.splitlines(True):
         match = reFilePath.match(line.rstrip('\r\n'))
         if match is not None:
+            matched = True
             filePath = match.group('file_path')
             filePath = os.path.normcase(os.path.abspath(filePath))
             if filePath != absSourceFile:
                 includesSet.add(filePath)
         elif strip:
             newOutput.append(line)
+    if prefix and not matched and compilerOutput.strip():
+        # The compiler may be worded differently than when it was probed, e.g. if
+        # the language pack probed got uninstalled since
+        return parseIncludesSet(compilerOutput, sourceFile, strip)
     if strip:
         return includesSet, ''.join(newOutput)
     else:
         return includesSet, compilerOutput
 
 
+def parseSourceDependencies(fileName):
+    """Reads the JSON file cl.exe writes for /sourceDependencies, returns SourceDependencies
+
+    Unlike /showIncludes output, the file also names the modules and header units
+    imported and the precompiled header used. Paths are normalized the way
+    parseIncludesSet() normalizes them.
+    """
+    with open(fileName, encoding='utf-8-sig') as f:
+        data = json.load(f)['Data']
+    normalize = lambda path: os.path.normcase(os.path.abspath(path))
+    return SourceDependencies(
+        {normalize(path) for path in data.get('Includes', [])},
+        {module['Name']: normalize(module['BMI']) for module in data.get('ImportedModules', [])},
+        {normalize(unit['Header']): normalize(unit['BMI']) for unit in data.get('ImportedHeaderUnits', [])},
+        normalize(data['PCH']) if data.get('PCH') else None)
+
+
+def collapseDirsInOutput(output):
+    """Replaces BASE_DIR and BUILD_DIR in paths in compiler output by placeholders"""
+    directories = [(d, p) for d, p in ((BASEDIR, OUTPUT_BASEDIR_PLACEHOLDER), (BUILDDIR, OUTPUT_BUILDDIR_PLACEHOLDER))
+                   if d]
+    # The build directory is often below the base directory
+    for directory, placeholder in sorted(directories, key=lambda d: -len(d[0])):
+        output = re.sub(re.escape(directory) + r'(?=[\\/])', lambda match, p=placeholder: p, output,
+                        flags=re.IGNORECASE)
+    return output
+
+
+def expandDirsInOutput(output):
+    if BASEDIR:
+        output = output.replace(OUTPUT_BASEDIR_PLACEHOLDER, BASEDIR)
+    return output.replace(OUTPUT_BUILDDIR_PLACEHOLDER, BUILDDIR)
+
+
 def addObjectToCache(stats, cache, cachekey, artifacts):
     # This function asserts that the caller locked 'section' and 'stats'
     # already and also saves them
     printTraceStatement("Adding file {} to cache using key {}".format(artifacts.objectFilePath, cachekey))
 
+    if 'CLCACHE_REMAP_OUTPUT' in os.environ:
+        # Warnings replayed from the cache should name the files of the checkout being built
+        artifacts = artifacts._replace(stdout=collapseDirsInOutput(artifacts.stdout),
+                                       stderr=collapseDirsInOutput(artifacts.stderr))
+
     size = cache.setEntry(cachekey, artifacts)
     if size is None:
         size = os.path.getsize(artifacts.objectFilePath)
@@ -1586,20 +2233,96 @@ def addObjectToCache(stats, cache, cachekey, artifacts):
         return stats.currentCacheSize() >= cfg.maximumCacheSize()
 
 
-def processCacheHit(cache, objectFile, cachekey):
+def processCacheHit(cache, objectFile, cachekey, cmdLine=None, restoreSourceDependencies=None):
     printTraceStatement("Reusing cached object for key {} for object file {}".format(cachekey, objectFile))
 
     with cache.lockFor(cachekey):
         with cache.statistics.lock, cache.statistics as stats:
             stats.registerCacheHit()
+        cache.history.record(objectFile, History.HIT, cmdLine=cmdLine)
 
         if os.path.exists(objectFile):
             os.remove(objectFile)
 
         cachedArtifacts = cache.getEntry(cachekey)
         copyOrLink(cachedArtifacts.objectFilePath, objectFile)
+        if restoreSourceDependencies is not None:
+            restoreSourceDependencies(cachedArtifacts.sourceDependencies)
         printTraceStatement("Finished. Exit code 0")
-        return 0, cachedArtifacts.stdout, cachedArtifacts.stderr, False
+        return 0, expandDirsInOutput(cachedArtifacts.stdout), expandDirsInOutput(cachedArtifacts.stderr), False
+
+
+def writeDepfile(objectFile, sourceFile, includePaths):
+    """Writes the files objectFile depends on to objectFile.d in the syntax of GCC's -MD if CLCACHE_DEPFILE is set"""
+    if 'CLCACHE_DEPFILE' not in os.environ:
+        return
+    escape = lambda path: path.replace('$', '$$').replace('#', '\\#').replace(' ', '\\ ')
+    lines = [escape(objectFile) + ':'] + [' ' + escape(path) for path in [sourceFile] + sorted(includePaths)]
+    with open(objectFile + '.d', 'w', encoding='utf-8') as f:
+        f.write(' \\\n'.join(lines) + '\n')
+
+
+def mapSourceDependenciesPaths(value, function):
+    """Returns the JSON value of a /sourceDependencies file with function applied to the strings in it"""
+    if isinstance(value, dict):
+        return {key: mapSourceDependenciesPaths(item, function) for key, item in value.items()}
+    elif isinstance(value, list):
+        return [mapSourceDependenciesPaths(item, function) for item in value]
+    elif isinstance(value, str):
+        return function(value)
+    return value
+
+
+def collapseSourceDependencies(content):
+    """Replaces BASE_DIR and BUILD_DIR in the paths of a /sourceDependencies file by placeholders"""
+    collapse = lambda value: collapseDirToPlaceholder(os.path.normcase(value)) if os.path.isabs(value) else value
+    return json.dumps(mapSourceDependenciesPaths(json.loads(content), collapse), indent=4)
+
+
+def readSourceDependencies(cmdLine, sourceFile):
+    """Returns the file cl.exe wrote for /sourceDependencies to store it in the cache, None if there is none"""
+    fileName = CommandLineAnalyzer.sourceDependenciesFileOf(cmdLine, sourceFile)
+    if fileName is None:
+        return None
+    try:
+        with open(fileName, encoding='utf-8-sig') as f:
+            return collapseSourceDependencies(f.read())
+    except (OSError, ValueError) as e:
+        printTraceStatement("Cannot read {}, not caching it: {}".format(fileName, e))
+        return None
+
+
+def writeSourceDependencies(cmdLine, sourceFile, includePaths, stored):
+    """Writes the file cl.exe would have written for /sourceDependencies, if it is given, on a cache hit
+
+    The file stored with the object is written if there is one. Direct mode
+    entries stored without it import no modules, so the file is made up from
+    their includes then.
+    """
+    fileName = CommandLineAnalyzer.sourceDependenciesFileOf(cmdLine, sourceFile)
+    if fileName is None:
+        return
+    if stored is not None:
+        with open(fileName, 'w', encoding='utf-8') as f:
+            json.dump(mapSourceDependenciesPaths(json.loads(stored), expandDirPlaceholder), f, indent=4)
+        return
+    if includePaths is None:
+        printTraceStatement("No {} stored with the cached object".format(fileName))
+        return
+    # The precompiled header and the interfaces referenced are among the
+    # dependencies, but not includes
+    pchFile = CommandLineAnalyzer.precompiledHeaderFileOf(cmdLine)
+    nonIncludes = CommandLineAnalyzer.referencedIfcFilesOf(cmdLine) + ([pchFile] if pchFile is not None else [])
+    nonIncludes = {os.path.normcase(os.path.abspath(path)) for path in nonIncludes}
+    data = {
+        'Source': os.path.normcase(os.path.abspath(sourceFile)),
+        'ProvidedModule': '',
+        'Includes': sorted(path for path in includePaths if path not in nonIncludes),
+        'ImportedModules': [],
+        'ImportedHeaderUnits': [],
+    }
+    with open(fileName, 'w', encoding='utf-8') as f:
+        json.dump({'Version': '1.1', 'Data': data}, f, indent=4)
 
 
 def createManifestEntry(manifestHash, includePaths):
@@ -1650,6 +2373,13 @@ def main():
     groupParser.add_argument("-M", "--set-size", dest="cache_size", type=int,
                              default=None,
                              help="set maximum cache size (in bytes)")
+    groupParser.add_argument("--report", dest="show_server_report",
+                             action="store_true",
+                             help="print the savings reported by clcachesrv")
+    groupParser.add_argument("--history", dest="history_days", type=int, nargs="?", const=30, default=None,
+                             metavar="DAYS",
+                             help="print the hit rate by day and the object files missed most often during the last "
+                                  "DAYS days (default: 30), as recorded with CLCACHE_HISTORY set")
 
     # This argument need to be optional, or it will be required for the status commands above
     parser.add_argument("compiler", default=None, action=CommandCheckAction,
@@ -1669,6 +2399,12 @@ def main():
         printStatistics(cache)
         return 0
 
+    if options.show_server_report:
+        return printServerReport()
+
+    if options.history_days is not None:
+        return printHistory(cache, options.history_days)
+
     if options.clean_cache:
         cleanCache(cache)
         print('Cache cleaned')
@@ -1732,6 +2468,13 @@ def processCompileRequest(cache, compiler, args):
     cmdLine, environment = extendCommandLineFromEnvironment(args, os.environ)
     cmdLine = expandCommandLine(cmdLine)
     printTraceStatement("Expanded commandline '{0!s}'".format(cmdLine))
+    fallbackCmdLine, fallbackEnvironment = args, None
+    if 'CLCACHE_Z7' in os.environ and embedDebugInfo(cmdLine) != cmdLine:
+        cmdLine = embedDebugInfo(cmdLine)
+        printTraceStatement("Rewritten commandline '{0!s}'".format(cmdLine))
+        # Uncacheable compiles must use /Z7, too, else the debug information formats of the
+        # objects and precompiled headers of a target would not match
+        fallbackCmdLine, fallbackEnvironment = cmdLine, environment
 
     try:
         sourceFiles, objectFiles = CommandLineAnalyzer.analyze(cmdLine)
@@ -1745,22 +2488,27 @@ def processCompileRequest(cache, compiler, args):
     except MultipleSourceFilesComplexError:
         printTraceStatement("Cannot cache invocation as {}: multiple source files found".format(cmdLine))
         updateCacheStatistics(cache, Statistics.registerCallWithMultipleSourceFiles)
-    except CalledWithPchError:
-        printTraceStatement("Cannot cache invocation as {}: precompiled headers in use".format(cmdLine))
+    except CalledWithPchError as e:
+        printTraceStatement("Cannot cache invocation as {}: precompiled headers in use, {}".format(cmdLine, e))
         updateCacheStatistics(cache, Statistics.registerCallWithPch)
+    except CalledWithModulesError as e:
+        printTraceStatement("Cannot cache invocation as {}: C++ modules in use, {}".format(cmdLine, e))
+        updateCacheStatistics(cache, Statistics.registerCallWithModules)
     except CalledForLinkError:
         printTraceStatement("Cannot cache invocation as {}: called for linking".format(cmdLine))
         updateCacheStatistics(cache, Statistics.registerCallForLinking)
     except ExternalDebugInfoError:
         printTraceStatement(
-            "Cannot cache invocation as {}: external debug information (/Zi) is not supported".format(cmdLine)
+            "Cannot cache invocation as {}: external debug information (/Zi) is not supported, "
+            "set CLCACHE_Z7 to use /Z7 instead".format(cmdLine)
         )
         updateCacheStatistics(cache, Statistics.registerCallForExternalDebugInfo)
     except CalledForPreprocessingError:
         printTraceStatement("Cannot cache invocation as {}: called for preprocessing".format(cmdLine))
         updateCacheStatistics(cache, Statistics.registerCallForPreprocessing)
 
-    exitCode, out, err = invokeRealCompiler(compiler, args)
+    cache.history.record(None, History.MISS, reason=History.UNCACHEABLE)
+    exitCode, out, err = invokeRealCompiler(compiler, fallbackCmdLine, environment=fallbackEnvironment)
     printOutAndErr(out, err)
     return exitCode
 
@@ -1819,12 +2567,12 @@ def processSingleSource(compiler, cmdLine, sourceFile, objectFile, environment):
 
         if 'CLCACHE_NODIRECT' in os.environ:
             printTraceStatement("Using non-direct mode")
-            return processNoDirect(cache, objectFile, compiler, cmdLine, environment)
+            return processNoDirect(cache, objectFile, compiler, cmdLine, sourceFile, environment)
         else:
             printTraceStatement("Using direct mode")
             return processDirect(cache, objectFile, compiler, cmdLine, sourceFile)
 
-    except IncludeNotFoundException:
+    except (IncludeNotFoundException, ToolchainUnknownException):
         return invokeRealCompiler(compiler, cmdLine, environment=environment), False
     except CompilerFailedException as e:
         return e.getReturnTuple()
@@ -1838,11 +2586,12 @@ def processDirect(cache, objectFile, compiler, cmdLine, sourceFile):
             for entryIndex, entry in enumerate(manifest.entries()):
                 # NOTE: command line options already included in hash for manifest name
                 try:
-                    includesContentHash = ManifestRepository.getIncludesContentHashForFiles(
-                        [expandDirPlaceholder(path) for path in entry.includeFiles])
+                    includeFiles = [expandDirPlaceholder(path) for path in entry.includeFiles]
+                    includesContentHash = ManifestRepository.getIncludesContentHashForFiles(includeFiles)
 
                     if entry.includesContentHash == includesContentHash:
                         cachekey = entry.objectHash
+                        includePaths = includeFiles
                         assert cachekey is not None
                         if entryIndex > 0:
                             # Move manifest entry to the top of the entries in the manifest
@@ -1852,14 +2601,24 @@ def processDirect(cache, objectFile, compiler, cmdLine, sourceFile):
                         manifestHit = True
                         with cache.lockFor(cachekey):
                             if cache.hasEntry(cachekey):
-                                return processCacheHit(cache, objectFile, cachekey)
+                                writeDepfile(objectFile, sourceFile, includePaths)
+                                return processCacheHit(
+                                    cache, objectFile, cachekey, cmdLine,
+                                    lambda stored: writeSourceDependencies(cmdLine, sourceFile, includePaths, stored))
 
                 except IncludeNotFoundException:
                     pass
 
-            unusableManifestMissReason = Statistics.registerHeaderChangedMiss
+            if manifestHit:
+                # The includes did not change, but the object is gone
+                unusableManifestMissReason = Statistics.registerEvictedMiss
+                missReason = History.EVICTED
+            else:
+                unusableManifestMissReason = Statistics.registerHeaderChangedMiss
+                missReason = History.HEADER_CHANGED
         else:
             unusableManifestMissReason = Statistics.registerSourceChangedMiss
+            missReason = cache.history.newKeyMissReason(objectFile, cmdLine)
 
     if manifestHit is None:
         stripIncludes = False
@@ -1867,15 +2626,38 @@ def processDirect(cache, objectFile, compiler, cmdLine, sourceFile):
             cmdLine = list(cmdLine)
             cmdLine.insert(0, '/showIncludes')
             stripIncludes = True
-    compilerResult = invokeRealCompiler(compiler, cmdLine, captureOutput=True)
+        showIncludesPrefix = getShowIncludesPrefix(cache, compiler)
+    compilerResult = invokeRealCompiler(compiler, mapSourcePaths(cmdLine), captureOutput=True, distribute=True)
     if manifestHit is None:
-        includePaths, compilerOutput = parseIncludesSet(compilerResult[1], sourceFile, stripIncludes)
+        includePaths, compilerOutput = parseIncludesSet(compilerResult[1], sourceFile, stripIncludes,
+                                                        showIncludesPrefix)
+        sourceDependenciesFile = CommandLineAnalyzer.sourceDependenciesFileOf(cmdLine, sourceFile)
+        if sourceDependenciesFile is not None and compilerResult[0] == 0:
+            try:
+                dependencies = parseSourceDependencies(sourceDependenciesFile)
+            except (OSError, ValueError, KeyError, TypeError) as e:
+                printTraceStatement("Cannot read {}, using /showIncludes output: {}".format(sourceDependenciesFile, e))
+            else:
+                # The file is stored with the object, changed interfaces of the
+                # modules and header units imported make for another object
+                includePaths = dependencies.includes | set(dependencies.modules.values()) | \
+                    set(dependencies.headerUnits.values())
+        pchFile = CommandLineAnalyzer.precompiledHeaderFileOf(cmdLine)
+        if pchFile is not None:
+            # The headers compiled into it are not listed by /showIncludes
+            includePaths.add(os.path.normcase(os.path.abspath(pchFile)))
+        for ifcFile in CommandLineAnalyzer.referencedIfcFilesOf(cmdLine):
+            includePaths.add(os.path.normcase(os.path.abspath(ifcFile)))
         compilerResult = (compilerResult[0], compilerOutput, compilerResult[2])
 
+    if compilerResult[0] == 0:
+        writeDepfile(objectFile, sourceFile, includePaths)
+
     with cache.manifestLockFor(manifestHash):
         if manifestHit is not None:
             return ensureArtifactsExist(cache, cachekey, unusableManifestMissReason,
-                                        objectFile, compilerResult)
+                                        objectFile, compilerResult, missReason=missReason, cmdLine=cmdLine,
+                                        sourceFile=sourceFile)
 
         entry = createManifestEntry(manifestHash, includePaths)
         cachekey = entry.objectHash
@@ -1886,22 +2668,28 @@ def processDirect(cache, objectFile, compiler, cmdLine, sourceFile):
             cache.setManifest(manifestHash, manifest)
 
         return ensureArtifactsExist(cache, cachekey, unusableManifestMissReason,
-                                    objectFile, compilerResult, addManifest)
+                                    objectFile, compilerResult, addManifest, missReason, cmdLine, sourceFile)
 
 
-def processNoDirect(cache, objectFile, compiler, cmdLine, environment):
+def processNoDirect(cache, objectFile, compiler, cmdLine, sourceFile, environment):
     cachekey = CompilerArtifactsRepository.computeKeyNodirect(compiler, cmdLine, environment)
     with cache.lockFor(cachekey):
         if cache.hasEntry(cachekey):
-            return processCacheHit(cache, objectFile, cachekey)
+            return processCacheHit(cache, objectFile, cachekey, cmdLine,
+                                   lambda stored: writeSourceDependencies(cmdLine, sourceFile, None, stored))
 
-    compilerResult = invokeRealCompiler(compiler, cmdLine, captureOutput=True, environment=environment)
+    compilerResult = invokeRealCompiler(compiler, mapSourcePaths(cmdLine), captureOutput=True,
+                                        environment=environment, distribute=True)
 
+    # The key covers the preprocessed source, so changed headers count as a changed source
+    missReason = cache.history.newKeyMissReason(objectFile, cmdLine)
     return ensureArtifactsExist(cache, cachekey, Statistics.registerCacheMiss,
-                                objectFile, compilerResult)
+                                objectFile, compilerResult, missReason=missReason, cmdLine=cmdLine,
+                                sourceFile=sourceFile)
 
 
-def ensureArtifactsExist(cache, cachekey, reason, objectFile, compilerResult, extraCallable=None):
+def ensureArtifactsExist(cache, cachekey, reason, objectFile, compilerResult, extraCallable=None, missReason=None,
+                         cmdLine=None, sourceFile=None):
     cleanupRequired = False
     returnCode, compilerOutput, compilerStderr = compilerResult
     correctCompiliation = (returnCode == 0 and os.path.exists(objectFile))
@@ -1910,8 +2698,11 @@ def ensureArtifactsExist(cache, cachekey, reason, objectFile, compilerResult, ex
             with cache.statistics.lock, cache.statistics as stats:
                 reason(stats)
                 if correctCompiliation:
-                    artifacts = CompilerArtifacts(objectFile, compilerOutput, compilerStderr)
+                    sourceDependencies = readSourceDependencies(cmdLine, sourceFile) if cmdLine else None
+                    artifacts = CompilerArtifacts(objectFile, compilerOutput, compilerStderr, sourceDependencies)
                     cleanupRequired = addObjectToCache(stats, cache, cachekey, artifacts)
+            cache.history.record(objectFile, History.MISS, os.path.getsize(objectFile) if correctCompiliation else 0,
+                                 missReason, cmdLine)
             if extraCallable and correctCompiliation:
                 extraCallable()
     return returnCode, compilerOutput, compilerStderr, cleanupRequired
@@ -1932,9 +2723,9 @@ if returnCode != 0:
         try:
             cProfile.run(CALL_SCRIPT, filename='clcache-{}.prof'.format(INVOCATION_HASH))
         except ProfilerError as e:
-            sys.exit(e.returnCode)
+            sys.exit(processExitCode(e.returnCode))
     else:
-        sys.exit(main())
+        sys.exit(processExitCode(main()))
 
 if __name__ == '__main__':
     mainWrapper()
@@ -0,0 +1,310 @@
+#
+# This file is part of the clcache project.
+#
+# The contents of this file are subject to the BSD 3-Clause License, the
+# full text of which is available in the accompanying LICENSE file at the
+# root directory of this project.
+#
+# Wire format spoken between clcache and clcachesrv.
+#
+# A legacy request is a newline-separated list of paths, terminated by a NUL
+# byte. A framed request additionally starts with a header line: the marker
+# character '>' followed by a JSON object. '>' cannot appear in Windows file
+# names, so the two formats can be told apart by looking at the first byte.
+#
+# The header may name the 'encoding' of the paths; UTF-8 (the default) and
+# UTF-16LE are supported. Since UTF-16 text contains NUL bytes, any other
+# encoding than UTF-8 requires the header to give the payload 'length' in
+# bytes, the terminator then follows the payload. A connection carries a single
+# request, any bytes after its terminator are rejected.
+#
+# Instead of paths, the request may consist of a single command line starting
+# with '*', e.g. '*watch C:\\src'.
+#
+# A successful response is the newline-separated list of hashes. If the header
+# asked for it ('"canonical": true'), each hash is followed by a tab and the
+# final path of the file hashed, i.e. after resolving path mappings, junctions
+# and symbolic links. If the header gives 'keys', an opaque string per path,
+# each line starts with the key of its path and a tab instead, and clients must
+# not rely on the order of the lines; see parseKeyedResponse(). The response is
+# still sent as a whole once all paths are hashed. If the header asks for
+# '"metadata": true', the hashes are digests of whether each file exists and of
+# its modification time and size, which the server computes without reading the
+# files; missing files are no error then. If the header asked for it
+# ('"sign": true'), a last line holds the marker character '@' and the base64
+# encoded Ed25519 signature of signedMessage(). A failed request is answered by
+# the marker character '!' followed by a JSON error record, see encodeError().
+# Records the server pushes to subscribed clients (see '*watch') are lines
+# starting with '~'; '~heartbeat' is sent periodically. Subscribers may send
+# heartbeats, too (any bytes will do), and are then expected to keep doing so.
+#
+import codecs
+import errno
+import hashlib
+import json
+
+PIPE_NAME = r'\\.\pipe\clcache_srv'
+HEADER_MARKER = b'>'
+ERROR_MARKER = b'!'
+TERMINATOR = b'\x00'
+COMMAND_MARKER = '*'
+NOTIFICATION_MARKER = '~'
+SIGNATURE_MARKER = '@'
+FIELD_SEPARATOR = '\t'
+DEFAULT_ENCODING = 'utf-8'
+SUPPORTED_ENCODINGS = ('utf-8', 'utf-16-le')
+
+
+class ErrorCode:
+    """Stable error identifiers; clients base retry/bypass decisions on these"""
+    NOT_FOUND = 'NotFound'
+    ACCESS_DENIED = 'AccessDenied'
+    TIMEOUT = 'Timeout'
+    TOO_LARGE = 'TooLarge'
+    CANCELED = 'Canceled'
+    OFFLINE = 'Offline'
+    EXCLUDED = 'Excluded'
+    UNSTABLE = 'Unstable'
+    BUSY = 'Busy'
+    OVERLOADED = 'Overloaded'
+    BAD_REQUEST = 'BadRequest'
+    INTERNAL = 'Internal'
+    OTHER = 'Other'
+
+    # Errors which concern the files asked for, as opposed to the server
+    FILE_ERRORS = {NOT_FOUND, ACCESS_DENIED, OFFLINE, BUSY}
+
+
+class Priority:
+    """Value of the 'priority' header field; requests are served in this order"""
+    INTERACTIVE = 'interactive'
+    BATCH = 'batch'
+
+    ALL = (INTERACTIVE, BATCH)
+
+
+class ProtocolError(Exception):
+    pass
+
+
+class FileOfflineError(OSError):
+    """The file content is not available locally, e.g. a cloud placeholder"""
+    def __init__(self, path):
+        super(FileOfflineError, self).__init__(None, "file content is not available locally", path)
+
+
+class FileExcludedError(OSError):
+    """The server was configured not to hash the file"""
+    def __init__(self, path):
+        super(FileExcludedError, self).__init__(None, "file is excluded from hashing", path)
+
+
+class FileUnstableError(OSError):
+    """The file kept changing while it was being hashed"""
+    def __init__(self, path):
+        super(FileUnstableError, self).__init__(None, "file changed while being hashed", path)
+
+
+class FileBusyError(OSError):
+    """Another process kept the file open for writing, e.g. a generated header still being written"""
+    def __init__(self, path):
+        super(FileBusyError, self).__init__(None, "file is open for writing", path)
+
+
+class FileTooLargeError(OSError):
+    """The file is larger than the server was configured to hash"""
+    def __init__(self, path, size):
+        super(FileTooLargeError, self).__init__(None, "file is too large to hash ({} bytes)".format(size), path)
+
+
+class ServerOverloadedError(Exception):
+    """The server serves as many clients as it may and has no room to queue another one"""
+    def __init__(self, retryAfter):
+        super(ServerOverloadedError, self).__init__("server is overloaded, retry after {:g} seconds".format(retryAfter))
+        self.retryAfter = retryAfter
+
+
+class ServerError(Exception):
+    def __init__(self, code, message, errorNumber=None, winerror=None, filename=None, retryAfter=None):
+        super(ServerError, self).__init__(message)
+        self.code = code
+        self.message = message
+        self.errno = errorNumber
+        self.winerror = winerror
+        self.filename = filename
+        self.retryAfter = retryAfter
+
+    def __str__(self):
+        return "{}: {}".format(self.code, self.message)
+
+    def toOSError(self):
+        # Files which are unavailable (including those which are offline or still
+        # being written) are reported as not found, which makes clcache treat the
+        # compile as a miss
+        errorNumber = self.errno
+        if errorNumber is None:
+            errorNumber = errno.EACCES if self.code == ErrorCode.ACCESS_DENIED else errno.ENOENT
+        return OSError(errorNumber, self.message, self.filename)
+
+
+def errorCodeForException(e):
+    if isinstance(e, ProtocolError):
+        return ErrorCode.BAD_REQUEST
+    if isinstance(e, FileOfflineError):
+        return ErrorCode.OFFLINE
+    if isinstance(e, FileExcludedError):
+        return ErrorCode.EXCLUDED
+    if isinstance(e, FileUnstableError):
+        return ErrorCode.UNSTABLE
+    if isinstance(e, FileTooLargeError):
+        return ErrorCode.TOO_LARGE
+    if isinstance(e, FileBusyError):
+        return ErrorCode.BUSY
+    if isinstance(e, ServerOverloadedError):
+        return ErrorCode.OVERLOADED
+    if isinstance(e, (FileNotFoundError, NotADirectoryError)):
+        return ErrorCode.NOT_FOUND
+    if isinstance(e, PermissionError):
+        return ErrorCode.ACCESS_DENIED
+    if isinstance(e, TimeoutError):
+        return ErrorCode.TIMEOUT
+    if isinstance(e, OSError) and e.errno == errno.ECANCELED:
+        return ErrorCode.CANCELED
+    return ErrorCode.OTHER
+
+
+def encodeError(e, code=None):
+    record = {
+        'error': code or errorCodeForException(e),
+        'message': (e.strerror if isinstance(e, OSError) else None) or str(e),
+        'errno': getattr(e, 'errno', None),
+        'winerror': getattr(e, 'winerror', None),
+        'path': getattr(e, 'filename', None),
+    }
+    if getattr(e, 'retryAfter', None) is not None:
+        record['retryAfter'] = e.retryAfter
+    return ERROR_MARKER + json.dumps(record).encode('utf-8')
+
+
+def decodeError(data):
+    try:
+        record = json.loads(data[len(ERROR_MARKER):].decode('utf-8'))
+        return ServerError(record['error'], record['message'], record.get('errno'), record.get('winerror'),
+                           record.get('path'), record.get('retryAfter'))
+    except (ValueError, KeyError, TypeError):
+        return ServerError(ErrorCode.OTHER, "malformed error response: {!r}".format(data))
+
+
+def signedMessage(paths, hashes):
+    """The data covered by the signature of a response, binding the hashes to the requested paths"""
+    return '\n'.join(paths).encode('utf-8') + TERMINATOR + '\n'.join(hashes).encode('utf-8')
+
+
+def echoHash(path):
+    """The hash a server started with --echo-mode answers for path (after mapping it and resolving it against cwd)"""
+    return hashlib.md5(path.encode('utf-8')).hexdigest()
+
+
+def validateKeys(keys, pathCount):
+    """Checks the 'keys' header field of a request for pathCount paths"""
+    if not isinstance(keys, list) or len(keys) != pathCount:
+        raise ProtocolError("header field 'keys' must be a list with a key for each of the {} paths".format(pathCount))
+    for key in keys:
+        if not isinstance(key, str) or FIELD_SEPARATOR in key or '\n' in key:
+            raise ProtocolError("keys must be strings without tabs or newlines: {!r}".format(key))
+
+
+def parseKeyedResponse(response):
+    """Maps the keys of a response to a request with 'keys' to the rest of their line (the hash, and path)"""
+    return dict(line.split(FIELD_SEPARATOR, 1) for line in response.decode('utf-8').splitlines()
+                if not line.startswith(SIGNATURE_MARKER))
+
+
+def encodeRequest(paths, header=None, encoding=DEFAULT_ENCODING):
+    payload = '\n'.join(paths).encode(encoding)
+    if encoding != DEFAULT_ENCODING:
+        header = dict(header or {}, encoding=encoding, length=len(payload))
+    if header:
+        payload = HEADER_MARKER + json.dumps(header).encode('utf-8') + b'\n' + payload
+    return payload + TERMINATOR
+
+
+def _splitHeader(data):
+    headerLine, _, data = data.partition(b'\n')
+    try:
+        header = json.loads(headerLine[len(HEADER_MARKER):].decode('utf-8'))
+    except ValueError as e:
+        raise ProtocolError("malformed request header: {}".format(e))
+    if not isinstance(header, dict):
+        raise ProtocolError("request header must be a JSON object")
+    return header, data
+
+
+def isCompleteRequest(data):
+    """Tells whether data (as read so far) holds a complete request, including the terminator"""
+    if data.startswith(HEADER_MARKER):
+        headerEnd = data.find(b'\n')
+        if headerEnd == -1:
+            return False
+        try:
+            header, _ = _splitHeader(data[:headerEnd])
+            length = header.get('length')
+        except ProtocolError:
+            length = None
+        if isinstance(length, int):
+            return len(data) >= headerEnd + 1 + length + len(TERMINATOR)
+    return data.endswith(TERMINATOR)
+
+
+def _trailingDataError(count):
+    return ProtocolError("{} bytes follow the terminator of the request; send one request per connection and give "
+                         "the payload 'length' in the header if the paths may contain NUL bytes".format(count))
+
+
+def parseRequest(data):
+    """Splits a complete request (without terminator) into header and paths"""
+    header = {}
+    if data.startswith(HEADER_MARKER):
+        header, data = _splitHeader(data)
+
+    encoding = header.get('encoding', DEFAULT_ENCODING)
+    try:
+        encoding = codecs.lookup(encoding).name
+    except (LookupError, TypeError):
+        raise ProtocolError("unknown encoding '{}'".format(encoding))
+    if encoding not in SUPPORTED_ENCODINGS:
+        raise ProtocolError("unsupported encoding '{}'".format(encoding))
+
+    length = header.get('length')
+    if length is not None:
+        if not isinstance(length, int) or length < 0:
+            raise ProtocolError("header field 'length' must be a non-negative integer")
+        if len(data) < length:
+            raise ProtocolError("payload is {} bytes, header announced {}".format(len(data), length))
+        if len(data) > length:
+            if data[length:length + len(TERMINATOR)] == TERMINATOR:
+                raise _trailingDataError(len(data) - length)
+            raise ProtocolError("payload of {} bytes announced by the header is not followed by the terminator"
+                                .format(length))
+    elif encoding != DEFAULT_ENCODING:
+        raise ProtocolError("encoding '{}' requires the payload length in the header".format(encoding))
+    elif TERMINATOR in data:
+        raise _trailingDataError(len(data) - data.index(TERMINATOR))
+
+    try:
+        return header, data.decode(encoding).splitlines()
+    except UnicodeDecodeError as e:
+        raise ProtocolError("paths are not valid {}: {}".format(encoding, e))
+
+
+def queryServer(command, lines=()):
+    """Sends a command (and its further lines) to the server currently owning the pipe, returns None if there is none"""
+    try:
+        with open(PIPE_NAME, 'w+b') as f:
+            f.write(encodeRequest([COMMAND_MARKER + command] + list(lines)))
+            response = f.read()[:-len(TERMINATOR)]
+    except FileNotFoundError:
+        return None
+    if response.startswith(ERROR_MARKER):
+        raise decodeError(response)
+    return response
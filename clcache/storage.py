@@ -82,6 +82,14 @@ class CacheMemcacheStrategy:
     def configuration(self):
         return self.fileStrategy.configuration
 
+    @property
+    def showIncludesPrefixesFile(self):
+        return self.fileStrategy.showIncludesPrefixesFile
+
+    @property
+    def history(self):
+        return self.fileStrategy.history
+
     @staticmethod
     def lockFor(_):
         return CacheDummyLock()
@@ -111,7 +119,8 @@ class CacheMemcacheStrategy:
 
         printTraceStatement("{} remote cache hit for {} dumping into local cache".format(self, key))
 
-        assert len(data) == 3
+        # Entries stored without a /sourceDependencies file have three parts
+        assert len(data) in (3, 4)
 
         # XX this is writing the remote objectfile into the local cache
         # because the current cache lookup assumes that getEntry gives us an Entry in local cache
@@ -122,17 +131,19 @@ class CacheMemcacheStrategy:
 
         return CompilerArtifacts(objectFilePath,
                                  data[1].decode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC),
-                                 data[2].decode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC)
+                                 data[2].decode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC),
+                                 data[3].decode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC) if len(data) > 3 else None
                                 )
 
     def setEntry(self, key, artifacts):
         assert artifacts.objectFilePath
         with open(artifacts.objectFilePath, 'rb') as objectFile:
-            self._setIgnoreExc(self.objectPrefix + key,
-                               [objectFile.read(),
-                                artifacts.stdout.encode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC),
-                                artifacts.stderr.encode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC)],
-                              )
+            data = [objectFile.read(),
+                    artifacts.stdout.encode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC),
+                    artifacts.stderr.encode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC)]
+        if artifacts.sourceDependencies is not None:
+            data.append(artifacts.sourceDependencies.encode(CACHE_COMPILER_OUTPUT_STORAGE_CODEC))
+        self._setIgnoreExc(self.objectPrefix + key, data)
 
     def setManifest(self, manifestHash, manifest):
         self._setIgnoreExc(self.manifestPrefix + manifestHash, manifest)
@@ -210,6 +221,14 @@ class CacheFileWithMemcacheFallbackStrategy:
     def configuration(self):
         return self.localCache.configuration
 
+    @property
+    def showIncludesPrefixesFile(self):
+        return self.localCache.showIncludesPrefixesFile
+
+    @property
+    def history(self):
+        return self.localCache.history
+
     @staticmethod
     def lockFor(_):
         return CacheDummyLock()
@@ -4,4 +4,4 @@ import os
 sys.path.insert(0, os.path.dirname(__file__))
 
 from clcache.server.__main__ import main
-main()
+sys.exit(main())
@@ -1,3 +1,14 @@
-from clcache.__main__ import mainWrapper
-import clcache.storage 
+import os
+import subprocess
+import sys
+
+if 'CLCACHE_DISABLE' in os.environ and os.path.isabs(os.environ.get('CLCACHE_CL', '')) and \
+        os.path.isfile(os.environ['CLCACHE_CL']):
+    # Skip loading clcache altogether, so disabling it tells its overhead in full
+    exitCode = subprocess.call([os.environ['CLCACHE_CL']] + sys.argv[1:]) & 0xFFFFFFFF
+    # Passed on as a negative number if it does not fit a C long, see processExitCode()
+    sys.exit(exitCode - (1 << 32) if exitCode > 0x7FFFFFFF else exitCode)
+
+from clcache.__main__ import mainWrapper  # pylint: disable=wrong-import-position
+import clcache.storage  # pylint: disable=wrong-import-position
 mainWrapper()
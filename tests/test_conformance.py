@@ -0,0 +1,180 @@
+#!/usr/bin/env python
+#
+# This file is part of the clcache project.
+#
+# The contents of this file are subject to the BSD 3-Clause License, the
+# full text of which is available in the accompanying LICENSE file at the
+# root directory of this project.
+#
+# Protocol conformance tests for clcachesrv. They talk to the server over the
+# named pipe and check its responses byte for byte, so a client can be validated
+# against any server release: if a server is running already, the tests use it,
+# otherwise they start the one given by CLCACHESRV_CMD (default: this checkout).
+#
+# In Python unittests are always members, not functions. Silence lint in this file.
+# pylint: disable=no-self-use
+#
+from ctypes import windll
+import hashlib
+import json
+import os
+import subprocess
+import sys
+import tempfile
+import time
+import unittest
+
+from clcache.server import protocol
+
+if "CLCACHESRV_CMD" in os.environ:
+    CLCACHESRV_CMD = os.environ['CLCACHESRV_CMD'].split()
+else:
+    CLCACHESRV_CMD = [sys.executable,
+                      os.path.join(os.path.dirname(os.path.dirname(os.path.realpath(__file__))), "clcachesrv.py")]
+
+SERVER_STARTUP_TIMEOUT = 10
+
+
+def serverIsRunning():
+    return windll.kernel32.WaitNamedPipeW(protocol.PIPE_NAME, 100) != 0
+
+
+def sendRequest(data):
+    with open(protocol.PIPE_NAME, 'w+b') as f:
+        f.write(data)
+        return f.read()
+
+
+def md5(path):
+    with open(path, 'rb') as f:
+        return hashlib.md5(f.read()).hexdigest().encode('ascii')
+
+
+class TestProtocolConformance(unittest.TestCase):
+    @classmethod
+    def setUpClass(cls):
+        cls.server = None
+        if not serverIsRunning():
+            cls.server = subprocess.Popen(CLCACHESRV_CMD)
+            deadline = time.monotonic() + SERVER_STARTUP_TIMEOUT
+            while not serverIsRunning():
+                if time.monotonic() > deadline or cls.server.poll() is not None:
+                    cls.server.kill()
+                    raise RuntimeError("clcachesrv did not start: {}".format(CLCACHESRV_CMD))
+                time.sleep(0.1)
+
+        cls.tempDir = tempfile.TemporaryDirectory()
+        cls.headers = []
+        for name in ('a.h', 'b.h', 'Jürgen.h', '山田.h'):
+            path = os.path.join(cls.tempDir.name, name)
+            with open(path, 'w', encoding='utf-8') as f:
+                f.write('#define HEADER "{}"\n'.format(name))
+            cls.headers.append(path)
+
+    @classmethod
+    def tearDownClass(cls):
+        cls.tempDir.cleanup()
+        if cls.server is not None:
+            cls.server.terminate()
+            cls.server.wait()
+
+    def assertError(self, response, code, path=None):
+        self.assertTrue(response.startswith(protocol.ERROR_MARKER), response)
+        self.assertTrue(response.endswith(protocol.TERMINATOR), response)
+        error = protocol.decodeError(response[:-len(protocol.TERMINATOR)])
+        self.assertEqual(error.code, code)
+        if path is not None:
+            self.assertEqual(os.path.normcase(error.filename), os.path.normcase(path))
+
+    def testLegacySinglePath(self):
+        a = self.headers[0]
+        self.assertEqual(sendRequest(a.encode('utf-8') + b'\x00'), md5(a) + b'\x00')
+
+    def testLegacyMultiplePaths(self):
+        data = '\n'.join(self.headers).encode('utf-8') + b'\x00'
+        self.assertEqual(sendRequest(data), b'\n'.join(md5(path) for path in self.headers) + b'\x00')
+
+    def testHeaderWithPathMap(self):
+        data = b'>' + json.dumps({'pathMap': {'Q:\\mapped': self.tempDir.name}}).encode('utf-8') + \
+            b'\nQ:\\mapped\\a.h\x00'
+        self.assertEqual(sendRequest(data), md5(self.headers[0]) + b'\x00')
+
+    def testRelativePathWithCwd(self):
+        data = protocol.encodeRequest(['a.h', os.path.join('..', os.path.basename(self.tempDir.name), 'b.h')],
+                                      {'cwd': self.tempDir.name})
+        self.assertEqual(sendRequest(data), md5(self.headers[0]) + b'\n' + md5(self.headers[1]) + b'\x00')
+
+    def testRelativeCwd(self):
+        self.assertError(sendRequest(protocol.encodeRequest(['a.h'], {'cwd': 'src'})),
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testCanonicalPaths(self):
+        data = b'>' + json.dumps({'pathMap': {'Q:\\mapped': self.tempDir.name}, 'canonical': True}).encode('utf-8') + \
+            b'\nQ:\\mapped\\a.h\x00'
+        hashsum, path = sendRequest(data)[:-1].decode('utf-8').split(protocol.FIELD_SEPARATOR)
+        self.assertEqual(hashsum.encode('ascii'), md5(self.headers[0]))
+        self.assertEqual(os.path.normcase(path), os.path.normcase(os.path.realpath(self.headers[0])))
+
+    def testKeyedResponse(self):
+        data = protocol.encodeRequest(self.headers[:2], {'keys': ['first', 'second']})
+        response = sendRequest(data)
+        self.assertTrue(response.endswith(b'\x00'))
+        hashes = [md5(path).decode('ascii') for path in self.headers[:2]]
+        self.assertEqual(protocol.parseKeyedResponse(response[:-1]), {'first': hashes[0], 'second': hashes[1]})
+        self.assertError(sendRequest(protocol.encodeRequest(self.headers[:2], {'keys': ['first']})),
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testMetadataOnly(self):
+        missing = os.path.join(self.tempDir.name, 'missing.h')
+        response = sendRequest(protocol.encodeRequest([self.headers[0], missing], {'metadata': True}))
+        self.assertFalse(response.startswith(protocol.ERROR_MARKER), response)
+        hashes = response[:-1].split(b'\n')
+        self.assertEqual(len(hashes), 2)
+        self.assertNotEqual(hashes[0], md5(self.headers[0]))
+
+    def testPriorities(self):
+        for priority in protocol.Priority.ALL:
+            data = protocol.encodeRequest(self.headers[:2], {'priority': priority})
+            self.assertEqual(sendRequest(data), md5(self.headers[0]) + b'\n' + md5(self.headers[1]) + b'\x00')
+
+    def testUtf16Payload(self):
+        paths = self.headers[2:]
+        data = protocol.encodeRequest(paths, encoding='utf-16-le')
+        self.assertEqual(sendRequest(data), b'\n'.join(md5(path) for path in paths) + b'\x00')
+
+    def testMissingFile(self):
+        missing = os.path.join(self.tempDir.name, 'missing.h')
+        self.assertError(sendRequest(protocol.encodeRequest([self.headers[0], missing])),
+                         protocol.ErrorCode.NOT_FOUND, missing)
+
+    def testMalformedHeader(self):
+        self.assertError(sendRequest(b'>{"priority": \n' + self.headers[0].encode('utf-8') + b'\x00'),
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testBytesAfterTerminator(self):
+        a, b = (path.encode('utf-8') for path in self.headers[:2])
+        self.assertError(sendRequest(a + b'\x00' + b + b'\x00'), protocol.ErrorCode.BAD_REQUEST)
+
+    def testUnknownPriority(self):
+        self.assertError(sendRequest(protocol.encodeRequest(self.headers[:1], {'priority': 'urgent'})),
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testUnknownCommand(self):
+        self.assertError(sendRequest(b'*frobnicate\x00'), protocol.ErrorCode.BAD_REQUEST)
+
+    def testStats(self):
+        response = sendRequest(b'*stats\x00')
+        self.assertTrue(response.endswith(b'\x00'))
+        self.assertIsInstance(json.loads(response[:-1].decode('utf-8')), dict)
+
+    def testCapabilities(self):
+        response = sendRequest(b'*capabilities\x00')
+        self.assertTrue(response.endswith(b'\x00'))
+        capabilities = json.loads(response[:-1].decode('utf-8'))
+        names = [algorithm['name'] for algorithm in capabilities['algorithms']]
+        self.assertIn(capabilities['current'], names)
+
+
+if __name__ == '__main__':
+    unittest.TestCase.longMessage = True
+    unittest.main()
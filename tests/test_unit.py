@@ -9,12 +9,16 @@
 # In Python unittests are always members, not functions. Silence lint in this file.
 # pylint: disable=no-self-use
 #
+from collections import Counter
 from contextlib import contextmanager
+import io
+import json
 import multiprocessing
 import os
 import unittest
 import tempfile
 import shutil
+import sys
 
 from clcache import __main__ as clcache
 
@@ -30,6 +34,9 @@ from clcache.__main__ import (
 from clcache.__main__ import (
     AnalysisError,
     CalledForLinkError,
+    CalledWithModulesError,
+    CalledWithPchError,
+    ExternalDebugInfoError,
     CalledForPreprocessingError,
     InvalidArgumentError,
     MultipleSourceFilesComplexError,
@@ -37,6 +44,7 @@ from clcache.__main__ import (
     PersistentJSONDict,
 )
 from clcache.storage import CacheMemcacheStrategy
+from clcache.server import protocol
 
 ASSETS_DIR = os.path.join(os.path.dirname(__file__), "unittests")
 
@@ -56,6 +64,35 @@ def temporaryFileName():
         return f.name
 
 
+class MemoryStream:
+    """The server end of a client connection held in memory, for serving clients without creating pipes"""
+    def __init__(self):
+        self.written = []
+        self.closed = False
+        self._readCallback = None
+
+    def start_read(self, callback):
+        self._readCallback = callback
+
+    def write(self, data, callback=None):
+        self.written.append(bytes(data))
+        if callback:
+            callback(self, None)
+
+    def close(self):
+        self.closed = True
+
+    def isReading(self):
+        return self._readCallback is not None
+
+    def send(self, data):
+        """Delivers data as if the client had written it"""
+        self._readCallback(self, data, None)
+
+    def hangUp(self, error):
+        self._readCallback(self, None, error)
+
+
 class TestHelperFunctions(unittest.TestCase):
     def testBasenameWithoutExtension(self):
         self.assertEqual(clcache.basenameWithoutExtension(r"README.asciidoc"), "README")
@@ -86,6 +123,79 @@ class TestHelperFunctions(unittest.TestCase):
         self.assertEqual(clcache.normalizeBaseDir("c:\\projects with space"), "c:\\projects with space")
         self.assertEqual(clcache.normalizeBaseDir("c:\\projects with ö"), "c:\\projects with ö")
 
+    def testProcessExitCode(self):
+        self.assertEqual(clcache.processExitCode(0), 0)
+        self.assertEqual(clcache.processExitCode(2), 2)
+        self.assertEqual(clcache.processExitCode(0xC0000005), -0x3FFFFFFB)
+        self.assertEqual(clcache.processExitCode(-0x3FFFFFFB), -0x3FFFFFFB)
+
+    def testCrashCodes(self):
+        self.assertFalse(clcache.isCrashCode(2))
+        self.assertTrue(clcache.isCrashCode(0xC0000005))
+        self.assertTrue(clcache.isCrashCode(-0x3FFFFFFB))
+        self.assertTrue(clcache.isCrashCode(0x80000003))
+        self.assertEqual(clcache.describeExitCode(0xC00000FD), "STATUS_STACK_OVERFLOW (0xC00000FD)")
+        self.assertEqual(clcache.describeExitCode(0xC0001234), "exception (0xC0001234)")
+
+    def testRemapOutput(self):
+        baseDir, buildDir = clcache.BASEDIR, clcache.BUILDDIR
+        try:
+            clcache.BASEDIR = os.path.normcase(os.path.join(os.sep, 'src', 'proj'))
+            clcache.BUILDDIR = os.path.join(clcache.BASEDIR, 'build')
+            output = os.path.join(os.sep, 'src', 'proj', 'build', 'a.h') + '(1): warning\n' + \
+                os.path.join(os.sep, 'src', 'proj', 'b.cpp') + '(2): warning\n' + \
+                os.path.join(os.sep, 'src', 'project2', 'c.cpp') + '(3): warning\n'
+            collapsed = clcache.collapseDirsInOutput(output)
+            self.assertEqual(collapsed.splitlines()[0], os.path.join('<CLCACHE_BUILDDIR>', 'a.h') + '(1): warning')
+            self.assertEqual(collapsed.splitlines()[1], os.path.join('<CLCACHE_BASEDIR>', 'b.cpp') + '(2): warning')
+            self.assertEqual(collapsed.splitlines()[2], output.splitlines()[2])
+
+            clcache.BASEDIR = os.path.normcase(os.path.join(os.sep, 'other'))
+            clcache.BUILDDIR = os.path.join(clcache.BASEDIR, 'out')
+            self.assertEqual(clcache.expandDirsInOutput(collapsed).splitlines()[:2],
+                             [os.path.join(clcache.BUILDDIR, 'a.h') + '(1): warning',
+                              os.path.join(clcache.BASEDIR, 'b.cpp') + '(2): warning'])
+        finally:
+            clcache.BASEDIR, clcache.BUILDDIR = baseDir, buildDir
+
+    def testMapSourcePaths(self):
+        baseDir = clcache.BASEDIR
+        try:
+            clcache.BASEDIR = r'c:\ws\proj'
+            os.environ.pop('CLCACHE_PATHMAP', None)
+            self.assertEqual(clcache.mapSourcePaths(['/c', 'main.cpp']), ['/c', 'main.cpp'])
+
+            os.environ['CLCACHE_PATHMAP'] = r'\src'
+            self.assertEqual(clcache.mapSourcePaths(['/c', 'main.cpp']),
+                             [r'/pathmap:c:\ws\proj=\src', '/c', 'main.cpp'])
+
+            clcache.BASEDIR = None
+            self.assertEqual(clcache.mapSourcePaths(['/c', 'main.cpp']), ['/c', 'main.cpp'])
+        finally:
+            clcache.BASEDIR = baseDir
+            os.environ.pop('CLCACHE_PATHMAP', None)
+
+    def testServerBackoff(self):
+        delays = [clcache.serverBackoff(attempt) for attempt in range(20)]
+        self.assertTrue(clcache.SERVER_BACKOFF_INITIAL / 2 <= delays[0] <= clcache.SERVER_BACKOFF_INITIAL)
+        self.assertTrue(2 * clcache.SERVER_BACKOFF_INITIAL <= delays[3] <= 8 * clcache.SERVER_BACKOFF_INITIAL)
+        self.assertTrue(all(delay <= clcache.SERVER_BACKOFF_MAX for delay in delays))
+        self.assertGreaterEqual(delays[-1], clcache.SERVER_BACKOFF_MAX / 2)
+
+    def testWriteDepfile(self):
+        with tempfile.TemporaryDirectory() as tempDir, cd(tempDir):
+            clcache.writeDepfile('main.obj', 'main.cpp', [r'c:\program files\include\stdio.h', 'a.h'])
+            self.assertFalse(os.path.exists('main.obj.d'))
+
+            os.environ['CLCACHE_DEPFILE'] = '1'
+            try:
+                clcache.writeDepfile('main.obj', 'main.cpp', [r'c:\program files\include\stdio.h', 'a.h'])
+            finally:
+                del os.environ['CLCACHE_DEPFILE']
+            with open('main.obj.d', encoding='utf-8') as f:
+                self.assertEqual(f.read(),
+                                 'main.obj: \\\n main.cpp \\\n a.h \\\n c:\\program\\ files\\include\\stdio.h\n')
+
     def testFilesBeneathSimple(self):
         with cd(os.path.join(ASSETS_DIR, "files-beneath")):
             files = list(clcache.filesBeneath("a"))
@@ -109,6 +219,13 @@ class TestHelperFunctions(unittest.TestCase):
             self.assertIn(r".\d\4.txt", files)
             self.assertIn(r".\d\e\5.txt", files)
 
+    def testEmbedDebugInfo(self):
+        self.assertEqual(clcache.embedDebugInfo(['/c', '/Zi', 'main.cpp']), ['/c', '/Z7', 'main.cpp'])
+        self.assertEqual(clcache.embedDebugInfo(['-c', '-ZI', '/Fdmain.pdb', 'main.cpp']),
+                         ['-c', '/Z7', '/Fdmain.pdb', 'main.cpp'])
+        self.assertEqual(clcache.embedDebugInfo(['/c', '/Z7', '/Zc:inline', 'Zi.cpp']),
+                         ['/c', '/Z7', '/Zc:inline', 'Zi.cpp'])
+
 
 class TestExtendCommandLineFromEnvironment(unittest.TestCase):
     def testEmpty(self):
@@ -153,6 +270,91 @@ class TestExtendCommandLineFromEnvironment(unittest.TestCase):
         self.assertEqual(env, {'USER': 'ab'})
 
 
+class TestStartServer(unittest.TestCase):
+    def _startServer(self, environment, frozen=None):
+        from unittest import mock
+
+        with mock.patch.dict(os.environ, environment), mock.patch.object(clcache.subprocess, 'Popen') as popen:
+            if frozen is None:
+                clcache.startServer()
+            else:
+                with mock.patch.object(sys, 'frozen', True, create=True), \
+                        mock.patch.object(sys, 'executable', os.path.join(frozen, 'clcache.exe')):
+                    clcache.startServer()
+        return [call[0][0] for call in popen.call_args_list]
+
+    def testDefault(self):
+        self.assertEqual(self._startServer({}), [[sys.executable, '-m', 'clcache.server']])
+
+    def testConfigured(self):
+        commandLine = r'"C:\Program Files\clcache\clcachesrv.exe" --idle-timeout 60'
+        self.assertEqual(self._startServer({'CLCACHE_SERVER_COMMAND': commandLine}),
+                         [[r'C:\Program Files\clcache\clcachesrv.exe', '--idle-timeout', '60']])
+
+    def testFrozen(self):
+        with tempfile.TemporaryDirectory() as tempDir:
+            # Without a server binary next to clcache.exe, the server is not started
+            self.assertEqual(self._startServer({}, frozen=tempDir), [])
+            serverBinary = os.path.join(tempDir, 'clcachesrv.exe')
+            with open(serverBinary, 'w'):
+                pass
+            self.assertEqual(self._startServer({}, frozen=tempDir), [[serverBinary]])
+
+
+class TestToolchainDigest(unittest.TestCase):
+    def setUp(self):
+        clcache.knownToolchainDigests.clear()
+        self.addCleanup(clcache.knownToolchainDigests.clear)
+
+    def _compilerHash(self, *responses):
+        """Returns the hash of a compiler while the server gives the responses (or raises them) in turn"""
+        from unittest import mock
+
+        responses = list(responses)
+
+        class Pipe(io.BytesIO):
+            def read(self, *args):
+                response = responses.pop(0)
+                if isinstance(response, Exception):
+                    raise response
+                return response
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            compiler = os.path.join(tempDir, 'cl.exe')
+            with open(compiler, 'wb') as f:
+                f.write(b'compiler')
+            with mock.patch.dict(os.environ, {'CLCACHE_SERVER': '1'}), \
+                    mock.patch.object(clcache, 'open', side_effect=lambda *args: Pipe(), create=True), \
+                    mock.patch.object(clcache, 'windll') as windll, mock.patch.object(clcache.time, 'sleep'):
+                windll.kernel32.GetLastError.return_value = clcache.ERROR_PIPE_BUSY
+                try:
+                    return clcache.getCompilerHash(compiler)
+                finally:
+                    self.assertEqual(responses, [])
+
+    def testBusyServer(self):
+        import errno
+
+        digest = json.dumps({'digest': 'toolchain'}).encode('utf-8') + protocol.TERMINATOR
+        fingerprinted = self._compilerHash(digest)
+        clcache.knownToolchainDigests.clear()
+        # The same key, even if the server has to be waited for
+        self.assertEqual(self._compilerHash(OSError(errno.EINVAL, "busy"), OSError(errno.EINVAL, "busy"), digest),
+                         fingerprinted)
+
+    def testNoAnswer(self):
+        with self.assertRaises(clcache.ToolchainUnknownException):
+            self._compilerHash(FileNotFoundError())
+        # Which is not remembered
+        self.assertEqual(clcache.knownToolchainDigests, {})
+
+    def testServerWithoutFingerprints(self):
+        unknownCommand = protocol.encodeError(protocol.ProtocolError("unknown command 'toolchain'")) + \
+            protocol.TERMINATOR
+        self.assertIsNotNone(self._compilerHash(unknownCommand))
+        self.assertEqual(list(clcache.knownToolchainDigests.values()), [None])
+
+
 class TestConfiguration(unittest.TestCase):
     def testOpenClose(self):
         with Configuration(temporaryFileName()):
@@ -174,6 +376,7 @@ class TestStatistics(unittest.TestCase):
             self.assertEqual(s.numCallsWithoutSourceFile(), 0)
             self.assertEqual(s.numCallsWithMultipleSourceFiles(), 0)
             self.assertEqual(s.numCallsWithPch(), 0)
+            self.assertEqual(s.numCallsWithModules(), 0)
             self.assertEqual(s.numCallsForLinking(), 0)
             self.assertEqual(s.numCallsForExternalDebugInfo(), 0)
             self.assertEqual(s.numEvictedMisses(), 0)
@@ -188,6 +391,7 @@ class TestStatistics(unittest.TestCase):
             s.registerCallWithoutSourceFile()
             s.registerCallWithMultipleSourceFiles()
             s.registerCallWithPch()
+            s.registerCallWithModules()
             s.registerCallForLinking()
             s.registerCallForExternalDebugInfo()
             s.registerEvictedMiss()
@@ -201,6 +405,7 @@ class TestStatistics(unittest.TestCase):
             self.assertEqual(s.numCallsWithoutSourceFile(), 1)
             self.assertEqual(s.numCallsWithMultipleSourceFiles(), 1)
             self.assertEqual(s.numCallsWithPch(), 1)
+            self.assertEqual(s.numCallsWithModules(), 1)
             self.assertEqual(s.numCallsForLinking(), 1)
             self.assertEqual(s.numCallsForExternalDebugInfo(), 1)
             self.assertEqual(s.numEvictedMisses(), 1)
@@ -213,6 +418,73 @@ class TestStatistics(unittest.TestCase):
             self.assertEqual(s.numCacheMisses(), 4)
 
 
+class TestHistory(unittest.TestCase):
+    def testRecord(self):
+        with tempfile.TemporaryDirectory() as tempDir:
+            history = clcache.History(os.path.join(tempDir, 'history.sqlite'))
+            history.record('main.obj', clcache.History.MISS, 100)
+            self.assertEqual(history.daily(30), [])
+
+            os.environ['CLCACHE_HISTORY'] = '1'
+            try:
+                history.record('main.obj', clcache.History.MISS, 100)
+                history.record('main.obj', clcache.History.HIT)
+                history.record('util.obj', clcache.History.MISS, 50)
+                history.record('main.obj', clcache.History.MISS, 20)
+            finally:
+                del os.environ['CLCACHE_HISTORY']
+
+            (_, hits, misses, bytesAdded), = history.daily(30)
+            self.assertEqual((hits, misses, bytesAdded), (1, 3, 170))
+            self.assertEqual(history.topMisses(30),
+                             [(os.path.normcase(os.path.abspath('main.obj')), 2),
+                              (os.path.normcase(os.path.abspath('util.obj')), 1)])
+            self.assertEqual(history.topMisses(30, limit=1)[0][1], 2)
+
+            history.clear()
+            self.assertEqual(history.daily(30), [])
+
+    def testMissReasons(self):
+        History = clcache.History
+        with tempfile.TemporaryDirectory() as tempDir:
+            history = History(os.path.join(tempDir, 'history.sqlite'))
+            flags = ['/c', '/O2', 'main.cpp']
+            os.environ['CLCACHE_HISTORY'] = '1'
+            try:
+                self.assertEqual(history.newKeyMissReason('main.obj', flags), History.NEW_SOURCE)
+                history.record('main.obj', History.MISS, 100, History.NEW_SOURCE, flags)
+                history.record('main.obj', History.HIT, cmdLine=['/showIncludes'] + flags)
+                self.assertEqual(history.newKeyMissReason('main.obj', flags), History.SOURCE_CHANGED)
+                self.assertEqual(history.newKeyMissReason('main.obj', ['/c', '/Od', 'main.cpp']),
+                                 History.FLAGS_CHANGED)
+                history.record('main.obj', History.MISS, 100, History.HEADER_CHANGED, flags)
+                history.record('util.obj', History.MISS, 100, History.HEADER_CHANGED, flags)
+                history.record(None, History.MISS, reason=History.UNCACHEABLE)
+            finally:
+                del os.environ['CLCACHE_HISTORY']
+            self.assertIsNone(history.newKeyMissReason('main.obj', flags))
+
+            self.assertEqual(history.missReasons(30),
+                             [(History.HEADER_CHANGED, 2), (History.NEW_SOURCE, 1), (History.UNCACHEABLE, 1)])
+            # Uncacheable compiles have no object file to blame
+            self.assertEqual([objectFile for objectFile, _ in history.topMisses(30)],
+                             [os.path.normcase(os.path.abspath(name)) for name in ('main.obj', 'util.obj')])
+
+    def testUpgrade(self):
+        import sqlite3
+        import time
+        with tempfile.TemporaryDirectory() as tempDir:
+            databaseFile = os.path.join(tempDir, 'history.sqlite')
+            connection = sqlite3.connect(databaseFile)
+            with connection:
+                connection.execute('CREATE TABLE compiles (time REAL NOT NULL, objectFile TEXT NOT NULL, '
+                                   'outcome TEXT NOT NULL, size INTEGER NOT NULL)')
+                connection.execute("INSERT INTO compiles VALUES (?, 'main.obj', 'miss', 10)", (time.time(),))
+            connection.close()
+
+            self.assertEqual(clcache.History(databaseFile).missReasons(30), [('unknown', 1)])
+
+
 class TestManifestRepository(unittest.TestCase):
     entry1 = ManifestEntry([r'somepath\myinclude.h'],
                            "fdde59862785f9f0ad6e661b9b5746b7",
@@ -461,6 +733,11 @@ class TestSplitCommandsFile(unittest.TestCase):
         self._genericTest(r'/nologo \foo.cpp', [r'/nologo', r'\foo.cpp'])
         self._genericTest(r'\foo.cpp /c', [r'\foo.cpp', r'/c'])
 
+    def testQuotesWithinQuotes(self):
+        self._genericTest(r'"-DNAME=""value"""', [r'-DNAME="value"'])
+        self._genericTest(r'"a""b" c', [r'a"b', 'c'])
+        self._genericTest(r'"a"" b" c', [r'a" b', 'c'])
+
 
 class TestExpandCommandLine(unittest.TestCase):
     def _genericTest(self, commandLine, expected):
@@ -477,6 +754,12 @@ class TestExpandCommandLine(unittest.TestCase):
     def testSingleResponseFile(self):
         self._genericTest(['-A', '@default_encoded.rsp', '-B'], ['-A', '/DPASSWORD=Käse', '/nologo', '-B'])
 
+    def testUtf8ResponseFileWithBom(self):
+        self._genericTest(['-A', '@utf8_bom_encoded.rsp', '-B'], ['-A', '/DPASSWORD=Käse', '/nologo', '-B'])
+
+    def testEmptyArgument(self):
+        self._genericTest(['-A', '', '-B'], ['-A', '', '-B'])
+
     def testMultipleResponseFile(self):
         self._genericTest(
             ['-A', '@default_encoded.rsp', '@utf16_encoded.rsp', '-B'],
@@ -564,6 +847,7 @@ class TestAnalyzeCommandLine(unittest.TestCase):
         self._testFailure(['/c'], NoSourceFileError)
         self._testFailure([], NoSourceFileError)
         self._testFailure(['/Zi'], NoSourceFileError)
+        self._testFailure(['/ZI'], NoSourceFileError)
         self._testFailure(['/E'], NoSourceFileError)
         self._testFailure(['/P'], NoSourceFileError)
         self._testFailure(['/EP'], NoSourceFileError)
@@ -637,6 +921,73 @@ class TestAnalyzeCommandLine(unittest.TestCase):
         self._testFo(r'/FoDebug//TheOutFile.obj', r'Debug\TheOutFile.obj')
         self._testFo(r'/FoDebug\\TheOutFile.obj', r'Debug\TheOutFile.obj')
 
+    def testExternalDebugInfo(self):
+        self._testFailure(['/c', '/Zi', 'main.cpp'], ExternalDebugInfoError)
+        self._testFailure(['/c', '/ZI', 'main.cpp'], ExternalDebugInfoError)
+        self._testFull(['/c', '/Z7', 'main.cpp'], [('main.cpp', '')], ['main.obj'])
+
+    def testPrecompiledHeaders(self):
+        self._testFailure(['/c', '/Ycstdafx.h', 'main.cpp'], CalledWithPchError)
+        self._testFailure(['/c', '/Yu', 'main.cpp'], CalledWithPchError)
+        self._testFailure(['/c', '/Yustdafx.h', '/FpDebug/', 'main.cpp'], CalledWithPchError)
+
+        with tempfile.TemporaryDirectory() as tempDir, cd(tempDir):
+            # The precompiled header file must have been created before
+            self._testFailure(['/c', '/Yustdafx.h', 'main.cpp'], CalledWithPchError)
+            with open('stdafx.pch', 'wb'):
+                pass
+            self._testFull(['/c', '/Yustdafx.h', 'main.cpp'], [('main.cpp', '')], ['main.obj'])
+            self._testFull(['/c', '/Yc', '/Y-', 'main.cpp'], [('main.cpp', '')], ['main.obj'])
+            self._testFailure(['/c', '/Yustdafx.h', '/Fpother.pch', 'main.cpp'], CalledWithPchError)
+
+    def testPrecompiledHeaderFile(self):
+        pchFileOf = CommandLineAnalyzer.precompiledHeaderFileOf
+        self.assertEqual(pchFileOf(['/c', '/Yustdafx.h', 'main.cpp']), 'stdafx.pch')
+        self.assertEqual(pchFileOf(['/c', '/Yuinclude/pch.hpp', 'main.cpp']), 'pch.pch')
+        self.assertEqual(pchFileOf(['/c', '/Yustdafx.h', '/FpDebug/project', 'main.cpp']),
+                         os.path.normpath('Debug/project.pch'))
+        self.assertEqual(pchFileOf(['/c', '/Yu', '/FpDebug/project.pch', 'main.cpp']),
+                         os.path.normpath('Debug/project.pch'))
+        self.assertIsNone(pchFileOf(['/c', '/Yu', 'main.cpp']))
+        self.assertIsNone(pchFileOf(['/c', '/Ycstdafx.h', 'main.cpp']))
+        self.assertIsNone(pchFileOf(['/c', '/Yustdafx.h', '/Y-', 'main.cpp']))
+
+    def testModules(self):
+        self._testFailure(['/c', '/interface', 'main.cpp'], CalledWithModulesError)
+        self._testFailure(['/c', '/internalPartition', 'main.cpp'], CalledWithModulesError)
+        self._testFailure(['/c', '/exportHeader', 'main.h'], CalledWithModulesError)
+        self._testFailure(['/c', '/ifcOutput', 'out.ifc', 'main.cpp'], CalledWithModulesError)
+        self._testFailure(['/c', 'core.ixx'], CalledWithModulesError)
+        self._testFailure(['/c', '/ifcSearchDir', 'modules', 'main.cpp'], CalledWithModulesError)
+
+        with tempfile.TemporaryDirectory() as tempDir, cd(tempDir):
+            # The interfaces referenced must have been created before
+            self._testFailure(['/c', '/reference', 'core=core.ifc', 'main.cpp'], CalledWithModulesError)
+            for name in ('core.ifc', 'util.h.ifc'):
+                with open(name, 'wb'):
+                    pass
+            self._testFull(['/c', '/reference', 'core=core.ifc', 'main.cpp'], [('main.cpp', '')], ['main.obj'])
+            self._testFull(['/c', '/reference', 'core.ifc', '/headerUnit:quote', 'util.h=util.h.ifc', 'main.cpp'],
+                           [('main.cpp', '')], ['main.obj'])
+
+    def testReferencedIfcFiles(self):
+        filesOf = CommandLineAnalyzer.referencedIfcFilesOf
+        self.assertEqual(filesOf(['/c', 'main.cpp']), [])
+        self.assertEqual(filesOf(['/c', '/reference', 'core=ifc/core.ifc', '/reference', 'util.ifc',
+                                  '/headerUnit', 'inc/a.h=a.h.ifc', '/headerUnit:angle', 'vector=vector.ifc',
+                                  'main.cpp']),
+                         [os.path.normpath('ifc/core.ifc'), 'util.ifc', 'a.h.ifc', 'vector.ifc'])
+
+    def testSourceDependenciesFile(self):
+        fileOf = CommandLineAnalyzer.sourceDependenciesFileOf
+        self.assertIsNone(fileOf(['/c', 'main.cpp'], 'main.cpp'))
+        self.assertEqual(fileOf(['/c', '/sourceDependencies', 'deps/main.json', 'main.cpp'], 'main.cpp'),
+                         os.path.normpath('deps/main.json'))
+        self.assertIsNone(fileOf(['/c', '/sourceDependencies:directives', 'main.json', 'main.cpp'], 'main.cpp'))
+        with tempfile.TemporaryDirectory() as tempDir:
+            self.assertEqual(fileOf(['/c', '/sourceDependencies', tempDir, 'src/main.cpp'], 'src/main.cpp'),
+                             os.path.join(tempDir, 'main.cpp.json'))
+
     def testPreprocessingFi(self):
         # Given output filename
         self._testFi('/FiTheOutFile.i')
@@ -702,6 +1053,8 @@ class TestAnalyzeCommandLine(unittest.TestCase):
         # Some documented, but non implemented
         self._testFailure(["/c", "/Xclang", "main.cpp"], NoSourceFileError)
         self._testSourceFilesOk(["/c", "/Xclang", "foo", "main.cpp"])
+        self._testFailure(["/c", "/sourceDependencies", "main.cpp"], NoSourceFileError)
+        self._testFull(["/c", "/sourceDependencies", "main.json", "main.cpp"], [("main.cpp", "")], ["main.obj"])
 
         # Documented as type 1 (/NAMEparmeter) but work as type 2 (/NAME[parameter])
         self._testSourceFilesOk(["/c", "/Fa", "main.cpp"])
@@ -971,6 +1324,139 @@ class TestParseIncludes(unittest.TestCase):
             r'c:\program files (x86)\microsoft visual studio 12.0\vc\include\concurrencysal.h' in includesSet)
         self.assertTrue(r'' not in includesSet)
 
+    SHOW_INCLUDES_PREFIXES = [
+        'Note: including file:',
+        'Hinweis: Einlesen der Datei:',
+        'Remarque : inclusion du fichier :',
+        'Remarque\u00a0: inclusion du fichier\u00a0:',
+        'Nota: inclusión del archivo:',
+        'Observação: incluindo arquivo:',
+        'Примечание: включение файла:',
+        'Uwaga: w tym pliku:',
+        'Poznámka: Včetně souboru:',
+        'Not: eklenen dosya:',
+        'メモ: インクルード ファイル:',
+        '注意: 包含文件:',
+        '참고: 포함 파일:',
+    ]
+
+    def testParseIncludesLocales(self):
+        header = r'C:\Program Files\Windows Kits\10\Include\ucrt\stdio.h'
+        for prefix in self.SHOW_INCLUDES_PREFIXES:
+            for explicitPrefix in (None, prefix):
+                compilerOutput = 'main.cpp\n{} {}\n{}  {}\n'.format(prefix, header, prefix, r'C:\src\a.h')
+                includesSet, newCompilerOutput = clcache.parseIncludesSet(
+                    compilerOutput, r'C:\src\main.cpp', strip=True, prefix=explicitPrefix)
+                self.assertEqual(
+                    includesSet,
+                    {os.path.normcase(os.path.abspath(path)) for path in (header, r'C:\src\a.h')},
+                    prefix)
+                self.assertEqual(newCompilerOutput, 'main.cpp\n', prefix)
+
+    def testParseIncludesExplicitPrefix(self):
+        # Lines which merely look like /showIncludes output are kept if the compiler's own wording is known
+        compilerOutput = 'main.cpp\nNota: file incluso  C:\\src\\a.h\nWarning: see also: C:\\src\\b.h\n'
+        includesSet, newCompilerOutput = clcache.parseIncludesSet(
+            compilerOutput, r'C:\src\main.cpp', strip=True, prefix='Nota: file incluso')
+        self.assertEqual(includesSet, {os.path.normcase(os.path.abspath(r'C:\src\a.h'))})
+        self.assertEqual(newCompilerOutput, 'main.cpp\nWarning: see also: C:\\src\\b.h\n')
+
+    def testParseIncludesStalePrefix(self):
+        # Output worded unlike the prefix probed is parsed as if the prefix were unknown
+        compilerOutput = 'main.cpp\nHinweis: Einlesen der Datei:  C:\\src\\a.h\n'
+        includesSet, newCompilerOutput = clcache.parseIncludesSet(
+            compilerOutput, r'C:\src\main.cpp', strip=True, prefix='Note: including file:')
+        self.assertEqual(includesSet, {os.path.normcase(os.path.abspath(r'C:\src\a.h'))})
+        self.assertEqual(newCompilerOutput, 'main.cpp\n')
+
+    def testShowIncludesPrefixPerLocale(self):
+        from types import SimpleNamespace
+        from unittest import mock
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            cache = SimpleNamespace(showIncludesPrefixesFile=os.path.join(tempDir, 'showincludes.txt'))
+            probes = []
+
+            def detect(compiler):
+                probes.append(os.environ.get('VSLANG'))
+                return 'Hinweis: Einlesen der Datei:' if os.environ.get('VSLANG') == '1031' else 'Note: including file:'
+
+            with mock.patch.object(clcache, 'getCompilerHash', return_value='toolchain'), \
+                    mock.patch.object(clcache, 'detectShowIncludesPrefix', side_effect=detect), \
+                    mock.patch.dict(os.environ, {'VSLANG': '1033'}):
+                self.assertEqual(clcache.getShowIncludesPrefix(cache, 'cl.exe'), 'Note: including file:')
+                os.environ['VSLANG'] = '1031'
+                self.assertEqual(clcache.getShowIncludesPrefix(cache, 'cl.exe'), 'Hinweis: Einlesen der Datei:')
+                self.assertEqual(clcache.getShowIncludesPrefix(cache, 'cl.exe'), 'Hinweis: Einlesen der Datei:')
+                os.environ['VSLANG'] = '1033'
+                self.assertEqual(clcache.getShowIncludesPrefix(cache, 'cl.exe'), 'Note: including file:')
+            self.assertEqual(probes, ['1033', '1031'])
+
+    def testParseShowIncludesPrefix(self):
+        for prefix in self.SHOW_INCLUDES_PREFIXES:
+            compilerOutput = 'clcache_probe.c\r\n{} {}\r\n'.format(
+                prefix, r'C:\Users\me\AppData\Local\Temp\tmp1x\CLCACHE_PROBE.H')
+            self.assertEqual(clcache.parseShowIncludesPrefix(compilerOutput, 'clcache_probe.h'), prefix)
+        self.assertIsNone(clcache.parseShowIncludesPrefix('clcache_probe.c\r\n', 'clcache_probe.h'))
+
+
+class TestSourceDependencies(unittest.TestCase):
+    def testParse(self):
+        dependencies = clcache.parseSourceDependencies(
+            os.path.join(ASSETS_DIR, 'parse-includes', 'source_dependencies.json'))
+        self.assertEqual(dependencies.includes, {
+            os.path.normcase(os.path.abspath(r'c:\projects\test\smartsqlite\include\smartsqlite\version.h')),
+            os.path.normcase(os.path.abspath(
+                r'c:\program files (x86)\microsoft visual studio 12.0\vc\include\concurrencysal.h')),
+        })
+        self.assertEqual(list(dependencies.modules), ['smartsqlite.core'])
+        self.assertEqual(len(dependencies.headerUnits), 1)
+        self.assertIsNone(dependencies.pch)
+
+    def testWriteOnCacheHit(self):
+        with tempfile.TemporaryDirectory() as tempDir, cd(tempDir):
+            includes = {os.path.normcase(os.path.abspath(name)) for name in ('a.h', 'b.h', 'stdafx.pch')}
+            clcache.writeSourceDependencies(['/c', '/Yustdafx.h', '/sourceDependencies', '.', 'main.cpp'],
+                                            'main.cpp', includes, None)
+            dependencies = clcache.parseSourceDependencies('main.cpp.json')
+            self.assertEqual(dependencies.includes, includes - {os.path.normcase(os.path.abspath('stdafx.pch'))})
+            self.assertEqual(dependencies.modules, {})
+
+    def testRestoreOnNodirectHit(self):
+        from unittest import mock
+
+        with tempfile.TemporaryDirectory() as tempDir, cd(tempDir):
+            cache = clcache.Cache(os.path.join(tempDir, 'cache'))
+            cmdLine = ['/c', '/sourceDependencies', '.', 'main.cpp']
+            normalize = lambda path: os.path.normcase(os.path.abspath(path))
+            data = {
+                'Source': normalize('main.cpp'),
+                'ProvidedModule': '',
+                'Includes': [normalize('a.h')],
+                'ImportedModules': [{'Name': 'core', 'BMI': normalize('core.ifc')}],
+                'ImportedHeaderUnits': [{'Header': normalize('b.h'), 'BMI': normalize('b.h.ifc')}],
+            }
+
+            def compileWritingDependencies(*args, **kwargs):
+                with open('main.obj', 'wb') as f:
+                    f.write(b'object')
+                with open('main.cpp.json', 'w') as f:
+                    json.dump({'Version': '1.1', 'Data': data}, f)
+                return 0, '', ''
+
+            with mock.patch.object(clcache.CompilerArtifactsRepository, 'computeKeyNodirect', return_value='a' * 32), \
+                    mock.patch.object(clcache, 'invokeRealCompiler',
+                                      side_effect=compileWritingDependencies) as invokeRealCompiler:
+                self.assertEqual(clcache.processNoDirect(cache, 'main.obj', 'cl.exe', cmdLine, 'main.cpp', {})[0], 0)
+                os.remove('main.cpp.json')
+                self.assertEqual(clcache.processNoDirect(cache, 'main.obj', 'cl.exe', cmdLine, 'main.cpp', {})[0], 0)
+            self.assertEqual(invokeRealCompiler.call_count, 1)
+
+            dependencies = clcache.parseSourceDependencies('main.cpp.json')
+            self.assertEqual(dependencies.includes, {normalize('a.h')})
+            self.assertEqual(dependencies.modules, {'core': normalize('core.ifc')})
+            self.assertEqual(dependencies.headerUnits, {normalize('b.h'): normalize('b.h.ifc')})
+
 
 class TestManifest(unittest.TestCase):
     entry1 = ManifestEntry([r'somepath\myinclude.h'],
@@ -1167,6 +1653,2075 @@ class TestCompression(unittest.TestCase):
             self.assertEqual(os.path.getsize(srcFilePath), os.path.getsize(dstFilePath))
 
 
+class TestServerProtocol(unittest.TestCase):
+    def testLegacyRequest(self):
+        data = protocol.encodeRequest([r"C:\Projects\a.h", r"C:\Projects\b.h"])
+        self.assertEqual(data, b"C:\\Projects\\a.h\nC:\\Projects\\b.h\x00")
+        self.assertEqual(protocol.parseRequest(data[:-1]), ({}, [r"C:\Projects\a.h", r"C:\Projects\b.h"]))
+
+    def testRequestWithHeader(self):
+        header = {"pathMap": {"X:\\src": "C:\\agent\\_work\\1\\s"}}
+        data = protocol.encodeRequest([r"X:\src\a.h"], header)
+        self.assertTrue(data.startswith(protocol.HEADER_MARKER))
+        self.assertEqual(protocol.parseRequest(data[:-1]), (header, [r"X:\src\a.h"]))
+
+    def testMalformedHeader(self):
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.parseRequest(b'>{"pathMap": \nC:\\a.h')
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.parseRequest(b'>[1, 2]\nC:\\a.h')
+
+    def testUtf16Request(self):
+        paths = [r"C:\Users\山田太郎\src\a.h", r"C:\Users\Jürgen Müller\src\ä.h"]
+        data = protocol.encodeRequest(paths, encoding='utf-16-le')
+        self.assertIn('山田太郎'.encode('utf-16-le'), data)
+        # The payload contains NUL bytes, only the announced length tells where it ends
+        self.assertFalse(protocol.isCompleteRequest(data[:-1]))
+        self.assertTrue(protocol.isCompleteRequest(data))
+        header, lines = protocol.parseRequest(data[:-1])
+        self.assertEqual(header['encoding'], 'utf-16-le')
+        self.assertEqual(lines, paths)
+
+    def testUtf8RequestWithEncodingHeader(self):
+        paths = [r"C:\Users\山田太郎\a.h", r"C:\Users\Jürgen\ä.h"]
+        data = protocol.encodeRequest(paths, {"encoding": "utf-8"})
+        self.assertTrue(protocol.isCompleteRequest(data))
+        self.assertEqual(protocol.parseRequest(data[:-1])[1], paths)
+
+    def testBadEncoding(self):
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.parseRequest(b'>{"encoding": "cp1252"}\nC:\\a.h')
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.parseRequest(b'>{"encoding": "utf-16-le"}\nC\x00:\x00')
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.parseRequest(b'C:\\J\xfcrgen\\a.h')
+
+    def testKeys(self):
+        protocol.validateKeys(['1', 'a.h'], 2)
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.validateKeys(['1'], 2)
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.validateKeys([1, 2], 2)
+        with self.assertRaises(protocol.ProtocolError):
+            protocol.validateKeys(['a\tb', 'c'], 2)
+        self.assertEqual(protocol.parseKeyedResponse(b'b\t2222\na\t1111\tC:\\a.h\n@c2lnbmF0dXJl'),
+                         {'a': '1111\tC:\\a.h', 'b': '2222'})
+
+    def testFraming(self):
+        paths = [r"C:\Users\山田太郎\a.h"]
+        for encoding in protocol.SUPPORTED_ENCODINGS:
+            data = protocol.encodeRequest(paths, {'length': len(paths[0].encode(encoding))}, encoding)
+            self.assertEqual(protocol.parseRequest(data[:-1])[1], paths)
+            # Bytes after the terminator, e.g. a second request on the same connection
+            with self.assertRaisesRegex(protocol.ProtocolError, "follow the terminator"):
+                protocol.parseRequest(data + data[:-1])
+            # A payload longer than announced
+            with self.assertRaisesRegex(protocol.ProtocolError, "not followed by the terminator"):
+                protocol.parseRequest(data[:-1] + b'x')
+        with self.assertRaisesRegex(protocol.ProtocolError, "follow the terminator"):
+            protocol.parseRequest(b'C:\\a.h\x00C:\\b.h')
+        with self.assertRaisesRegex(protocol.ProtocolError, "non-negative integer"):
+            protocol.parseRequest(b'>{"length": -1}\nC:\\a.h')
+
+    def testErrorRecords(self):
+        notFound = FileNotFoundError(2, "No such file", r"C:\Projects\missing.h")
+        error = protocol.decodeError(protocol.encodeError(notFound))
+        self.assertEqual(error.code, protocol.ErrorCode.NOT_FOUND)
+        self.assertEqual(error.errno, 2)
+        self.assertEqual(error.filename, r"C:\Projects\missing.h")
+        with self.assertRaises(FileNotFoundError):
+            raise error.toOSError()
+
+        denied = protocol.decodeError(protocol.encodeError(PermissionError(13, "Access is denied", "a.h")))
+        self.assertEqual(denied.code, protocol.ErrorCode.ACCESS_DENIED)
+        self.assertIsInstance(denied.toOSError(), PermissionError)
+
+        self.assertEqual(protocol.decodeError(protocol.encodeError(TimeoutError())).code,
+                         protocol.ErrorCode.TIMEOUT)
+        self.assertEqual(protocol.decodeError(protocol.encodeError(protocol.ProtocolError("bad"))).code,
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testOfflineErrorRecord(self):
+        error = protocol.decodeError(protocol.encodeError(protocol.FileOfflineError(r"C:\OneDrive\a.h")))
+        self.assertEqual(error.code, protocol.ErrorCode.OFFLINE)
+        self.assertEqual(error.filename, r"C:\OneDrive\a.h")
+        self.assertIsInstance(error.toOSError(), FileNotFoundError)
+
+    def testExcludedErrorRecord(self):
+        error = protocol.decodeError(protocol.encodeError(protocol.FileExcludedError(r"C:\Windows\a.h")))
+        self.assertEqual(error.code, protocol.ErrorCode.EXCLUDED)
+        self.assertNotIn(error.code, protocol.ErrorCode.FILE_ERRORS)
+
+    def testUnstableErrorRecord(self):
+        error = protocol.decodeError(protocol.encodeError(protocol.FileUnstableError(r"C:\build\gen.h")))
+        self.assertEqual(error.code, protocol.ErrorCode.UNSTABLE)
+        self.assertNotIn(error.code, protocol.ErrorCode.FILE_ERRORS)
+
+    def testBusyErrorRecord(self):
+        error = protocol.decodeError(protocol.encodeError(protocol.FileBusyError(r"C:\build\gen.h")))
+        self.assertEqual(error.code, protocol.ErrorCode.BUSY)
+        self.assertIn(error.code, protocol.ErrorCode.FILE_ERRORS)
+        self.assertIsInstance(error.toOSError(), FileNotFoundError)
+
+    def testTooLargeErrorRecord(self):
+        error = protocol.decodeError(protocol.encodeError(protocol.FileTooLargeError(r"C:\build\app.pdb", 2 ** 34)))
+        self.assertEqual(error.code, protocol.ErrorCode.TOO_LARGE)
+        self.assertEqual(error.filename, r"C:\build\app.pdb")
+
+    def testMalformedErrorRecord(self):
+        self.assertEqual(protocol.decodeError(b'!not json').code, protocol.ErrorCode.OTHER)
+
+
+class TestServerIdleMonitor(unittest.TestCase):
+    class FakeClock:
+        def __init__(self):
+            self.now = 0.0
+
+        def __call__(self):
+            return self.now
+
+    def testDisabled(self):
+        from clcache.server.__main__ import IdleMonitor
+
+        clock = TestServerIdleMonitor.FakeClock()
+        monitor = IdleMonitor(0, clock)
+        clock.now = 1e6
+        self.assertFalse(monitor.isIdle())
+
+    def testIdleWithoutClients(self):
+        from clcache.server.__main__ import IdleMonitor
+
+        clock = TestServerIdleMonitor.FakeClock()
+        monitor = IdleMonitor(180, clock)
+        clock.now = 179
+        self.assertFalse(monitor.isIdle())
+        clock.now = 180
+        self.assertTrue(monitor.isIdle())
+
+    def testLongRequestSurvivesTimeout(self):
+        from clcache.server.__main__ import IdleMonitor
+
+        clock = TestServerIdleMonitor.FakeClock()
+        monitor = IdleMonitor(180, clock)
+        monitor.connectionOpened()
+        # Hashing a large include set on a slow share for ten minutes
+        clock.now = 600
+        self.assertFalse(monitor.isIdle())
+        monitor.connectionClosed()
+
+        # The timeout only starts once the request was answered
+        clock.now = 779
+        self.assertFalse(monitor.isIdle())
+        clock.now = 780
+        self.assertTrue(monitor.isIdle())
+
+    def testIdleOnlyWhenAllConnectionsClosed(self):
+        from clcache.server.__main__ import IdleMonitor
+
+        clock = TestServerIdleMonitor.FakeClock()
+        monitor = IdleMonitor(180, clock)
+        monitor.connectionOpened()
+        monitor.connectionOpened()
+        monitor.connectionClosed()
+        clock.now = 1000
+        self.assertFalse(monitor.isIdle())
+        monitor.connectionClosed()
+        clock.now = 1180
+        self.assertTrue(monitor.isIdle())
+
+
+class TestServerHashCache(unittest.TestCase):
+    def testRevalidationDropsChangedEntries(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            paths = [os.path.join(tempDir, name) for name in ('a.h', 'b.h', 'c.h')]
+            for path in paths:
+                with open(path, 'w') as f:
+                    f.write(path)
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True, revalidateCount=2)
+            for path in paths:
+                cache.getFileHash(path)
+            with open(paths[0], 'w') as f:
+                f.write("changed")
+            with open(paths[2], 'w') as f:
+                f.write("changed")
+
+            cache.revalidate()
+            # a.h was not among the two most recently used entries
+            self.assertEqual(cache.stats['revalidated'], 2)
+            self.assertEqual(cache.stats['revalidationInvalidated'], 1)
+            self.assertEqual(cache.getFileHash(paths[2]), HashCache._computeHash(paths[2]))
+            self.assertEqual(cache.stats['misses'], 4)
+
+    def testRestoreSnapshotSkipsChangedFiles(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            unchanged = os.path.join(tempDir, 'unchanged.h')
+            changed = os.path.join(tempDir, 'changed.h')
+            for path in (unchanged, changed):
+                with open(path, 'w') as f:
+                    f.write(path)
+
+            loop = pyuv.Loop.default_loop()
+            previous = HashCache(loop, [], True)
+            previous.getFileHash(unchanged)
+            previous.getFileHash(changed)
+            snapshot = json.loads(json.dumps(previous.snapshot()))
+            with open(changed, 'w') as f:
+                f.write("changed")
+
+            cache = HashCache(loop, [], True)
+            self.assertEqual(cache.restore(snapshot), 1)
+            self.assertEqual(cache.getFileHash(unchanged), previous.getFileHash(unchanged))
+            self.assertEqual(cache.stats['hits'], 1)
+            self.assertEqual(cache.getFileHash(changed), HashCache._computeHash(changed))
+            self.assertEqual(cache.stats['misses'], 1)
+
+    def testJournalSurvivesRestart(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, Journal, Namespaces
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            paths = [os.path.join(tempDir, name) for name in ('a.h', 'b.h')]
+            for path in paths:
+                with open(path, 'w') as f:
+                    f.write(path)
+            journalFile = os.path.join(tempDir, 'journal')
+
+            loop = pyuv.Loop.default_loop()
+            previous = Namespaces(lambda: HashCache(loop, [], True, journaled=True))
+            journal = Journal(journalFile)
+            journal.compact(previous)
+            previous.get().getFileHash(paths[0])
+            previous.get('x64').getFileHash(paths[1])
+            journal.append(previous)
+            journal.close()
+
+            namespaces = Namespaces(lambda: HashCache(loop, [], True))
+            journal = Journal(journalFile)
+            self.assertEqual(namespaces.restore(journal.replay()), 2)
+            self.assertEqual(namespaces.get('x64').getFileHash(paths[1]), HashCache._computeHash(paths[1]))
+            self.assertEqual(namespaces.get('x64').stats['hits'], 1)
+            journal.close()
+
+    def testRestoreSkipsOtherAlgorithms(self):
+        import pyuv
+        import hashlib
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'w') as f:
+                f.write("#define A")
+
+            loop = pyuv.Loop.default_loop()
+            previous = HashCache(loop, [], True)
+            previous.getFileHash(header)
+            snapshot = previous.snapshot()
+            self.assertEqual(snapshot[os.path.normcase(tempDir)]['a.h'][-1], 'md5/1')
+
+            self.assertEqual(HashCache(loop, [], True).restore(snapshot), 1)
+            cache = HashCache(loop, [], True, hashAlgorithm='sha256')
+            self.assertEqual(cache.restore(snapshot), 0)
+            self.assertEqual(cache.stats['restoreTagMismatches'], 1)
+            self.assertEqual(cache.getFileHash(header), HashCache._computeHash(header, hashlib.sha256))
+
+    def testContentMasks(self):
+        import pyuv
+        from clcache.server.__main__ import ContentMask, HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            headers = []
+            for name, stamp in (('a_version.h', '10:00'), ('b_version.h', '11:30'), ('c.h', '10:00')):
+                headers.append(os.path.join(tempDir, name))
+                with open(headers[-1], 'w') as f:
+                    f.write("// Built at {}\n#define VERSION 1\n".format(stamp))
+
+            masks = [ContentMask.parse('*_version.h=// Built at [0-9:]+')]
+            loop = pyuv.Loop.default_loop()
+            cache = HashCache(loop, [], True, contentMasks=masks)
+            self.assertEqual(cache.getFileHash(headers[0]), cache.getFileHash(headers[1]))
+            self.assertNotEqual(cache.getFileHash(headers[0]), cache.getFileHash(headers[2]))
+            self.assertEqual(cache.getFileHash(headers[2]), HashCache._computeHash(headers[2]))
+            self.assertEqual(cache.stats['maskedMatches'], 2)
+
+            # Hashsums of files masked otherwise are not adopted
+            snapshot = cache.snapshot()
+            self.assertEqual(HashCache(loop, [], True).restore(snapshot), 0)
+            self.assertEqual(HashCache(loop, [], True, contentMasks=masks).restore(snapshot), 3)
+
+        with self.assertRaises(ValueError):
+            ContentMask.parse('*.h')
+        with self.assertRaises(ValueError):
+            ContentMask.parse('*.h=(unbalanced')
+
+    def testJournalPrefixQueries(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, Journal, Namespaces
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            paths = []
+            for subDir in ('src', 'src2', os.path.join('src', 'sub')):
+                os.mkdir(os.path.join(tempDir, subDir))
+                paths.append(os.path.join(tempDir, subDir, 'a.h'))
+                with open(paths[-1], 'w') as f:
+                    f.write(subDir)
+
+            loop = pyuv.Loop.default_loop()
+            namespaces = Namespaces(lambda: HashCache(loop, [], True, journaled=True))
+            journal = Journal(os.path.join(tempDir, 'journal'))
+            for path in paths:
+                namespaces.get().getFileHash(path)
+            journal.append(namespaces)
+
+            # Below src, but not src2
+            prefix = os.path.join(tempDir, 'src') + os.sep
+            self.assertEqual(namespaces.get().restore(journal.replay('', prefix)['']), 2)
+            self.assertEqual(journal.replay('x64', prefix), {})
+            self.assertEqual(journal.clear('', prefix), 2)
+            self.assertEqual(list(journal.replay()['']), [os.path.normcase(os.path.dirname(paths[1]))])
+            journal.close()
+
+    def testJournalMigration(self):
+        import contextlib
+        import sqlite3
+        from clcache.server.__main__ import Journal, LayoutError
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            fileName = os.path.join(tempDir, 'journal')
+            layout = Journal.layout()
+            self.assertEqual(layout.pending(fileName), [])
+
+            # A journal written before it became an SQLite database, with a superseded and an incomplete record
+            with open(fileName, 'w', encoding='utf-8') as f:
+                f.write(json.dumps(['', 'c:\\src', 'a.h', 'old', 1.0, 10]) + '\n')
+                f.write(json.dumps(['', 'c:\\src', 'a.h', 'new', 2.0, 20]) + '\n')
+                f.write('["", "c:\\\\src", "b.h"')
+            self.assertEqual([version for version, _ in layout.pending(fileName)], [0, 1])
+            layout.migrate(fileName)
+            self.assertEqual(layout.pending(fileName), [])
+            self.assertFalse(os.path.exists(fileName + '.migrating'))
+
+            # The records do not tell which algorithm their hashsums were computed with
+            journal = Journal(fileName)
+            self.assertEqual(journal.replay(), {'': {'c:\\src': {'a.h': ['new', 2.0, 20, None]}}})
+            journal.close()
+
+            # A journal from before the hashsums were tagged
+            os.remove(fileName)
+            with contextlib.closing(sqlite3.connect(fileName)) as db:
+                db.execute('CREATE TABLE hashsums (namespace TEXT, dirname TEXT, basename TEXT, hashsum TEXT, '
+                           'mtime, size, PRIMARY KEY (namespace, dirname, basename)) WITHOUT ROWID')
+                db.execute("INSERT INTO hashsums VALUES ('', 'c:\\src', 'a.h', 'old', 1.0, 10)")
+                db.commit()
+            self.assertEqual([version for version, _ in layout.pending(fileName)], [1])
+            layout.migrate(fileName)
+            journal = Journal(fileName)
+            self.assertEqual(journal.replay(), {'': {'c:\\src': {'a.h': ['old', 1.0, 10, None]}}})
+            journal.close()
+
+            layout.version = 0
+            with self.assertRaises(LayoutError):
+                layout.pending(fileName)
+
+    def testStoreLayout(self):
+        from clcache.server.__main__ import Layout, LayoutError
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            store = os.path.join(tempDir, 'store')
+            migrated = []
+            layout = Layout.directoryLayout('store', 2, {1: ("rename things", migrated.append)})
+            self.assertEqual(layout.pending(store), [])
+
+            # Unversioned store directories have the layout from before versioning
+            os.mkdir(store)
+            self.assertEqual(layout.pending(store), [(1, "rename things")])
+            with open(store + '.migrating', 'w'):
+                pass
+            with self.assertRaises(LayoutError):
+                layout.migrate(store)
+            self.assertEqual(migrated, [])
+
+            os.remove(store + '.migrating')
+            layout.migrate(store)
+            self.assertEqual(migrated, [store])
+            self.assertEqual(layout.pending(store), [])
+            self.assertFalse(os.path.exists(store + '.migrating'))
+
+    def testUsnRecordsInvalidateEntries(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, UsnRecord, USN_REASON_RENAME_OLD_NAME
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            subDir = os.path.join(tempDir, 'sub')
+            os.mkdir(subDir)
+            a, b = os.path.join(subDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            for path in (a, b):
+                with open(path, 'w') as f:
+                    f.write(path)
+            volume = os.stat(tempDir).st_dev
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              journaledVolumes={os.path.splitdrive(tempDir)[0]: volume})
+            cache.getFileHash(a)
+            cache.getFileHash(b)
+            self.assertEqual(cache._handlers, [])
+
+            # a.h was modified
+            record = UsnRecord(0, os.stat(a).st_ino, os.stat(subDir).st_ino, 1, 0x2, 'a.h')
+            self.assertEqual(cache.applyUsnRecord(volume, record), 1)
+            self.assertEqual(cache.applyUsnRecord(volume + 1, record), 0)
+            cache.getFileHash(a)
+            self.assertEqual(cache.stats['misses'], 3)
+
+            # sub was renamed
+            record = UsnRecord(0, os.stat(subDir).st_ino, os.stat(tempDir).st_ino, 2, USN_REASON_RENAME_OLD_NAME,
+                               'sub')
+            self.assertEqual(cache.applyUsnRecord(volume, record), 1)
+
+            # Another file was renamed to b.h
+            record = UsnRecord(0, 12345, os.stat(tempDir).st_ino, 3, 0x2000, 'b.h')
+            self.assertEqual(cache.applyUsnRecord(volume, record), 1)
+            self.assertEqual(list(cache.usage()), [])
+            self.assertEqual(cache.stats['journalInvalidated'], 3)
+
+    def testFinalPaths(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, UsnRecord, USN_REASON_RENAME_OLD_NAME
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            subDir = os.path.join(tempDir, 'sub')
+            os.mkdir(subDir)
+            a, b = os.path.join(subDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            for path in (a, b):
+                with open(path, 'w') as f:
+                    f.write(path)
+            volume = os.stat(tempDir).st_dev
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              journaledVolumes={os.path.splitdrive(tempDir)[0]: volume})
+            cache.getFileHash(a)
+            self.assertEqual(cache.finalPath(a), os.path.realpath(a))
+            self.assertEqual(cache.finalPath(a), os.path.realpath(a))
+            self.assertEqual(cache.stats['finalPathHits'], 1)
+
+            # Files in directories not watched are resolved every time
+            cache.finalPath(b)
+            cache.finalPath(b)
+            self.assertEqual(cache.stats['finalPathMisses'], 3)
+
+            # a.h was replaced
+            record = UsnRecord(0, os.stat(a).st_ino, os.stat(subDir).st_ino, 1, 0x2, 'a.h')
+            cache.applyUsnRecord(volume, record)
+            cache.finalPath(a)
+            self.assertEqual(cache.stats['finalPathMisses'], 4)
+
+            # sub was renamed
+            record = UsnRecord(0, os.stat(subDir).st_ino, os.stat(tempDir).st_ino, 2, USN_REASON_RENAME_OLD_NAME,
+                               'sub')
+            cache.applyUsnRecord(volume, record)
+            cache.finalPath(a)
+            self.assertEqual(cache.stats['finalPathMisses'], 5)
+            self.assertEqual(cache.stats['finalPathHits'], 1)
+
+    def testMaxFileSize(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+        from clcache.server.protocol import FileTooLargeError
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            small, large = os.path.join(tempDir, 'small.h'), os.path.join(tempDir, 'large.pdb')
+            with open(small, 'wb') as f:
+                f.write(b'x' * 10)
+            with open(large, 'wb') as f:
+                f.write(b'x' * 11)
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True, maxFileSize=10)
+            self.assertEqual(cache.getFileHash(small), HashCache._computeHash(small))
+            with self.assertRaises(FileTooLargeError):
+                cache.getFileHash(large)
+            self.assertEqual(cache.stats['tooLarge'], 1)
+
+    def testFilesOpenForWriting(self):
+        import pyuv
+        from clcache.server import __main__ as server
+        from clcache.server.__main__ import HashCache, HashJob, PathMap
+        from clcache.server.protocol import FileBusyError
+
+        class FakeConnection:
+            def __init__(self):
+                self.responses = []
+
+            def respond(self, response, trace):
+                self.responses.append(response)
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'gen.h')
+            with open(header, 'w') as f:
+                f.write("#define GENERATED")
+
+            isOpenForWriting = server.isOpenForWriting
+            try:
+                server.isOpenForWriting = lambda path: True
+                cache = HashCache(pyuv.Loop.default_loop(), [], True)
+                with self.assertRaises(FileBusyError):
+                    cache.getFileHash(header)
+                self.assertEqual(cache.stats['busy'], 1)
+
+                connection = FakeConnection()
+                job = HashJob(connection, cache, [header], PathMap.parse(None))
+                for _ in range(HashJob.BUSY_RETRIES):
+                    self.assertFalse(job.step(1))
+                    self.assertEqual(job.retryAfter, HashJob.BUSY_DELAY)
+                self.assertTrue(job.step(1))
+                self.assertTrue(connection.responses[0].startswith(b'!'))
+
+                # Once the writer is done, the job picks up where it was put aside
+                job = HashJob(connection, cache, [header], PathMap.parse(None))
+                self.assertFalse(job.step(1))
+                server.isOpenForWriting = lambda path: False
+                self.assertTrue(job.step(1))
+                self.assertEqual(connection.responses[1], HashCache._computeHash(header).encode('ascii'))
+            finally:
+                server.isOpenForWriting = isOpenForWriting
+
+    def testKeyedResponse(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, HashJob, PathMap
+
+        class FakeConnection:
+            def __init__(self):
+                self.responses = []
+
+            def respond(self, response, trace):
+                self.responses.append(response)
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            paths = [os.path.join(tempDir, name) for name in ('a.h', 'b.h')]
+            for path in paths:
+                with open(path, 'w') as f:
+                    f.write(path)
+
+            connection = FakeConnection()
+            job = HashJob(connection, HashCache(pyuv.Loop.default_loop(), [], True), paths, PathMap.parse(None),
+                          keys=['first', 'second'])
+            self.assertTrue(job.step(2))
+            self.assertEqual(protocol.parseKeyedResponse(connection.responses[0]),
+                             {'first': HashCache._computeHash(paths[0]), 'second': HashCache._computeHash(paths[1])})
+
+    def testMetadataHash(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            response = os.path.join(tempDir, 'args.rsp')
+            with open(response, 'w') as f:
+                f.write("/c main.cpp")
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True)
+            missing = cache.getMetadataHash(response + '.missing')
+            first = cache.getMetadataHash(response)
+            self.assertNotEqual(first, missing)
+            self.assertNotEqual(first, HashCache._computeHash(response))
+            self.assertEqual(cache.getMetadataHash(response), first)
+
+            with open(response, 'w') as f:
+                f.write("/c main.cpp /O2")
+            self.assertNotEqual(cache.getMetadataHash(response), first)
+            self.assertEqual(cache.stats['metadataHashes'], 4)
+            self.assertEqual(cache.stats['misses'], 0)
+
+    def testNetworkPaths(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, NetworkPolicy
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'w') as f:
+                f.write("content")
+
+            # With a TTL of zero, every hit checks the file again
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              networkPolicy=NetworkPolicy(0, isNetworkDrive=lambda drive: True))
+            cache.getFileHash(header)
+            cache.getFileHash(header)
+            self.assertEqual(cache.stats['networkRechecks'], 1)
+            self.assertEqual(cache.stats['misses'], 2)
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              networkPolicy=NetworkPolicy(0, True, isNetworkDrive=lambda drive: True))
+            cache.getFileHash(header)
+            self.assertEqual(cache.getFileHash(header), HashCache._computeHash(header))
+            self.assertEqual(cache.stats['hits'], 1)
+            with open(header, 'w') as f:
+                f.write("changed content")
+            self.assertEqual(cache.getFileHash(header), HashCache._computeHash(header))
+            self.assertEqual(cache.stats['misses'], 2)
+
+    def testEchoMode(self):
+        import pyuv
+        from clcache.server.__main__ import EchoCache
+        from clcache.server.protocol import echoHash
+
+        cache = EchoCache(pyuv.Loop.default_loop(), [], False)
+        path = os.path.join('does', 'not', 'exist.h')
+        self.assertEqual(cache.getFileHash(path), echoHash(path))
+        self.assertEqual(cache.getFileHash(path), echoHash(path))
+        self.assertNotEqual(echoHash(path), echoHash(path + 'pp'))
+        self.assertEqual(cache.finalPath(path), path)
+        self.assertEqual(cache.stats['echoed'], 2)
+
+    def testUsageCountsHits(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'w') as f:
+                f.write("content")
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True)
+            for _ in range(3):
+                cache.getFileHash(header)
+            (path, size, hits, _), = cache.usage()
+            self.assertEqual(path, os.path.normcase(header))
+            self.assertEqual(size, 7)
+            self.assertEqual(hits, 2)
+
+    def testContentCacheSharedBetweenCaches(self):
+        import pyuv
+        from clcache.server.__main__ import ContentCache, HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'w') as f:
+                f.write("content")
+
+            contentCache = ContentCache(1024)
+            loop = pyuv.Loop.default_loop()
+            first = HashCache(loop, [], True, contentCache=contentCache)
+            second = HashCache(loop, [], True, contentCache=contentCache)
+            self.assertEqual(first.getFileHash(header), second.getFileHash(header))
+            self.assertEqual(first.stats['contentHits'], 0)
+            self.assertEqual(second.stats['contentHits'], 1)
+
+    def testClear(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            generated = os.path.join(tempDir, 'generated')
+            os.mkdir(generated)
+            paths = [os.path.join(tempDir, 'a.h'), os.path.join(generated, 'b.h'), os.path.join(generated, 'c.h')]
+            for path in paths:
+                with open(path, 'w') as f:
+                    f.write(path)
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True)
+            for path in paths:
+                cache.getFileHash(path)
+
+            self.assertEqual(cache.clear(prefix=generated + os.sep, olderThan=3600), 0)
+            self.assertEqual(cache.clear(prefix=generated), 2)
+            self.assertEqual([entry[0] for entry in cache.usage()], [os.path.normcase(paths[0])])
+            self.assertEqual(cache.clear(), 1)
+
+    def testFileIdIndexIsPruned(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            a, b = os.path.join(tempDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            with open(a, 'w') as f:
+                f.write('#pragma once\n')
+            os.link(a, b)
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True)
+            self.assertEqual(cache.getFileHash(a), cache.getFileHash(b))
+            # The hashsum the links share is kept while one of them is cached
+            self.assertEqual(cache._invalidate(os.path.normcase(tempDir), 'a.h'), 1)  # pylint: disable=protected-access
+            self.assertEqual(len(cache._hashesByFileId), 1)  # pylint: disable=protected-access
+            self.assertEqual(cache.clear(), 1)
+            self.assertEqual(cache._hashesByFileId, {})  # pylint: disable=protected-access
+
+            c = os.path.join(tempDir, 'c.h')
+            with open(c, 'w') as f:
+                f.write('#pragma once\n// c\n')
+            cache.maxEntries = 1
+            cache.getFileHash(a)
+            cache.getFileHash(b)
+            self.assertEqual(cache.stats['quotaEvicted'], 1)
+            self.assertEqual(len(cache._hashesByFileId), 1)  # pylint: disable=protected-access
+            cache.getFileHash(c)
+            self.assertEqual(cache.stats['quotaEvicted'], 2)
+            # pylint: disable=protected-access
+            entry = cache._watchedDirectories[os.path.normcase(tempDir)]['c.h']
+            self.assertEqual(list(cache._hashesByFileId), [entry.fileId])
+
+    def testVerifySampledHits(self):
+        import pyuv
+        from clcache.server.__main__ import ContentCache, HashCache, UsnRecord
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            a, b = os.path.join(tempDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            with open(a, 'w') as f:
+                f.write('one')
+            os.link(a, b)
+            stat = os.stat(a)
+
+            volume = stat.st_dev
+            cache = HashCache(pyuv.Loop.default_loop(), [], True, verifySampleRate=1, contentCache=ContentCache(1024),
+                              journaledVolumes={os.path.splitdrive(tempDir)[0]: volume})
+            stale = cache.getFileHash(a)
+            self.assertEqual(cache.getFileHash(b), stale)
+            self.assertEqual(cache.stats['verificationFailures'], 0)
+
+            # Changed without a notification, and with a timestamp too coarse to tell
+            with open(a, 'w') as f:
+                f.write('two')
+            os.utime(a, ns=(stat.st_atime_ns, stat.st_mtime_ns))
+            fresh = HashCache(pyuv.Loop.default_loop(), [], True).getFileHash(a)
+            self.assertNotEqual(fresh, stale)
+            self.assertEqual(cache.getFileHash(a), fresh)
+            self.assertEqual(cache.getFileHash(b), fresh)
+            self.assertEqual(cache.stats['verificationFailures'], 2)
+
+            # The hashsums stored again are indexed like any other
+            self.assertEqual(cache.applyUsnRecord(volume, UsnRecord(0, stat.st_ino, os.stat(tempDir).st_ino, 1,
+                                                                    0x1, 'a.h')), 2)
+            self.assertEqual(len(cache), 0)
+
+    def testCollectGenerations(self):
+        import pyuv
+        from clcache.server.__main__ import Generations, HashCache
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            toolchain, generated = os.path.join(tempDir, 'stdio.h'), os.path.join(tempDir, 'generated.h')
+            for path in (toolchain, generated):
+                with open(path, 'w') as f:
+                    f.write(path)
+
+            now = [0]
+            generations = Generations(gap=60, clock=lambda: now[0])
+            cache = HashCache(pyuv.Loop.default_loop(), [], True, generations=generations)
+            # stdio.h is used in three builds, generated.h in the first one only
+            for build in range(3):
+                now[0] = build * 100
+                cache.getFileHash(toolchain)
+                if build == 0:
+                    cache.getFileHash(generated)
+            self.assertEqual(generations.current, 2)
+
+            self.assertEqual(cache.collect(3), 0)
+            self.assertEqual(cache.collect(2), 1)
+            self.assertEqual([entry[0] for entry in cache.usage()], [os.path.normcase(toolchain)])
+
+            # Tenured entries are kept TENURED_LIFETIME_FACTOR times as long
+            generations.current += 2 * HashCache.TENURED_LIFETIME_FACTOR - 1
+            self.assertEqual(cache.collect(2), 0)
+            generations.current += 1
+            self.assertEqual(cache.collect(2), 1)
+            self.assertEqual(cache.stats['collected'], 2)
+
+    def testHashAlgorithms(self):
+        import hashlib
+        import pyuv
+        from clcache.server.__main__ import HASHERS, HashCache, registerHasher
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'wb') as f:
+                f.write(b'content')
+
+            loop = pyuv.Loop.default_loop()
+            cache = HashCache(loop, [], True, hashAlgorithm='sha256')
+            self.assertEqual(cache.getFileHash(header), hashlib.sha256(b'content').hexdigest())
+
+            registerHasher('sha1', hashlib.sha1)
+            try:
+                cache = HashCache(loop, [], True, hashAlgorithm='sha1')
+                self.assertEqual(cache.getFileHash(header), hashlib.sha1(b'content').hexdigest())
+            finally:
+                del HASHERS['sha1']
+
+    def testTelemetryAccumulatesRuns(self):
+        from clcache.server.__main__ import Telemetry
+
+        class FakeCache:
+            stats = Counter(bytesNotRehashed=3000, coldHashBytes=1000, coldHashSeconds=0.5)
+
+        namespaces = {'': FakeCache()}
+        with tempfile.TemporaryDirectory() as tempDir:
+            telemetryFile = os.path.join(tempDir, 'telemetry.json')
+            Telemetry(telemetryFile).save(namespaces)
+            report = Telemetry(telemetryFile).report(namespaces)
+            self.assertEqual(report['totalBytesNotRehashed'], 6000)
+            self.assertEqual(report['totalColdHashBytes'], 2000)
+            self.assertAlmostEqual(report['estimatedSecondsSaved'], 3.0)
+
+    def testFindInclude(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, NetworkPolicy, UsnRecord
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            first, second = os.path.join(tempDir, 'first'), os.path.join(tempDir, 'second')
+            os.makedirs(os.path.join(first, 'sys'))
+            os.makedirs(os.path.join(second, 'sys'))
+            header = os.path.join(second, 'sys', 'types.h')
+            with open(header, 'w') as f:
+                f.write("second")
+
+            cache = HashCache(pyuv.Loop.default_loop(), [], True)
+            found = cache.findInclude(os.path.join('sys', 'types.h'), [first, second])
+            self.assertEqual(os.path.normcase(found), os.path.normcase(header))
+
+            # Without directory watching, the result is not cached
+            shadowing = os.path.join(first, 'sys', 'types.h')
+            with open(shadowing, 'w') as f:
+                f.write("first")
+            found = cache.findInclude(os.path.join('sys', 'types.h'), [first, second])
+            self.assertEqual(os.path.normcase(found), os.path.normcase(shadowing))
+
+            with self.assertRaises(FileNotFoundError):
+                cache.findInclude('missing.h', [first, second])
+
+            # Directories on network shares are not watched, so the result is not cached there either
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              networkPolicy=NetworkPolicy(isNetworkDrive=lambda drive: True))
+            cache.findInclude(os.path.join('sys', 'types.h'), [first, second])
+            cache.findInclude(os.path.join('sys', 'types.h'), [first, second])
+            self.assertEqual(cache.stats['includeMisses'], 2)
+            self.assertEqual(cache._handlers, [])  # pylint: disable=protected-access
+
+            # On journaled volumes, the change journal tells about new files instead of a watch
+            volume = os.stat(tempDir).st_dev
+            cache = HashCache(pyuv.Loop.default_loop(), [], False,
+                              journaledVolumes={os.path.splitdrive(tempDir)[0]: volume})
+            os.remove(shadowing)
+            self.assertEqual(os.path.normcase(cache.findInclude(os.path.join('sys', 'types.h'), [first, second])),
+                             os.path.normcase(header))
+            self.assertEqual(os.path.normcase(cache.findInclude(os.path.join('sys', 'types.h'), [first, second])),
+                             os.path.normcase(header))
+            self.assertEqual(cache.stats['includeHits'], 1)
+            self.assertEqual(cache._handlers, [])  # pylint: disable=protected-access
+            with open(shadowing, 'w') as f:
+                f.write("first")
+            cache.applyUsnRecord(volume, UsnRecord(0, os.stat(shadowing).st_ino,
+                                                   os.stat(os.path.dirname(shadowing)).st_ino, 1, 0x100, 'types.h'))
+            self.assertEqual(os.path.normcase(cache.findInclude(os.path.join('sys', 'types.h'), [first, second])),
+                             os.path.normcase(shadowing))
+
+    def testSplitStream(self):
+        from clcache.server.__main__ import splitStream
+
+        header = os.path.join('src', 'a.h')
+        self.assertEqual(splitStream(header), (header, ''))
+        self.assertEqual(splitStream(header + ':Zone.Identifier'), (header, 'Zone.Identifier'))
+        self.assertEqual(splitStream(header + ':meta:$DATA'), (header, 'meta'))
+        self.assertEqual(splitStream(header + '::$DATA'), (header, ''))
+
+    def testParseUsnRecord(self):
+        import struct
+        from clcache.server.__main__ import parseUsnRecord
+
+        name = 'a.h'.encode('utf-16-le')
+        v2 = struct.pack('<IHHQQqqIIIIHH', 60 + len(name), 2, 0, 7, 5, 1234, 0, 0x2, 0, 0, 0x20, len(name), 60) + name
+        record = parseUsnRecord(v2)
+        self.assertEqual((record.fileId, record.parentId, record.usn, record.reason, record.name),
+                         (7, 5, 1234, 0x2, 'a.h'))
+
+        v3 = struct.pack('<IHHQQQQqqIIIIHH', 76 + len(name), 3, 0, 7, 1, 5, 0, 4321, 0, 0x100, 0, 0, 0x20,
+                         len(name), 76) + name
+        record = parseUsnRecord(v3)
+        self.assertEqual((record.fileId, record.parentId, record.usn, record.reason, record.name),
+                         (1 << 64 | 7, 5, 4321, 0x100, 'a.h'))
+
+        with self.assertRaises(ValueError):
+            parseUsnRecord(struct.pack('<IHH', 8, 4, 0))
+
+    def testParseVersion(self):
+        from clcache.server.__main__ import parseVersion
+
+        self.assertEqual(parseVersion("4.2.1-dev"), (4, 2, 1))
+        self.assertGreater(parseVersion("4.10.0"), parseVersion("4.9.3"))
+
+
+class TestServerContentCache(unittest.TestCase):
+    def testVersionMismatch(self):
+        from clcache.server.__main__ import ContentCache
+
+        cache = ContentCache(100)
+        cache.put('a.h', (1, 3), b'abc')
+        self.assertEqual(cache.get('a.h', (1, 3)), b'abc')
+        self.assertIsNone(cache.get('a.h', (2, 3)))
+
+    def testBudget(self):
+        from clcache.server.__main__ import ContentCache
+
+        cache = ContentCache(10)
+        cache.put('a.h', (1, 4), b'aaaa')
+        cache.put('b.h', (1, 4), b'bbbb')
+        cache.get('a.h', (1, 4))
+        cache.put('c.h', (1, 4), b'cccc')
+        self.assertIsNone(cache.get('b.h', (1, 4)))
+        self.assertEqual(cache.get('a.h', (1, 4)), b'aaaa')
+        self.assertEqual(cache.get('c.h', (1, 4)), b'cccc')
+
+        cache.put('large.h', (1, 11), b'x' * 11)
+        self.assertIsNone(cache.get('large.h', (1, 11)))
+
+
+class TestServerObjectStore(unittest.TestCase):
+    def testPutAndGet(self):
+        from clcache.server.__main__ import ObjectStore
+        from clcache.server.protocol import ProtocolError
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            source, target = os.path.join(tempDir, 'a.obj'), os.path.join(tempDir, 'b.obj')
+            with open(source, 'wb') as f:
+                f.write(b'object')
+
+            store = ObjectStore(os.path.join(tempDir, 'store'), 10000)
+            self.assertEqual(store.put('0123abcd', source), 6)
+            self.assertEqual(store.get('0123abcd', target), 6)
+            with open(target, 'rb') as f:
+                self.assertEqual(f.read(), b'object')
+            with self.assertRaises(FileNotFoundError):
+                store.get('4567abcd', target)
+            with self.assertRaises(ProtocolError):
+                store.put('..\\evil', source)
+            self.assertEqual((store.stats['objectHits'], store.stats['objectMisses']), (1, 1))
+
+            # The objects survive restarts
+            store = ObjectStore(os.path.join(tempDir, 'store'), 10000)
+            self.assertEqual((len(store), store.size()), (1, ObjectStore.ALLOCATION_UNIT))
+
+    def testSweepsOrphans(self):
+        import time
+        from clcache.server.__main__ import ORPHAN_AGE, ManifestStore, ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            objects, manifests = os.path.join(tempDir, 'objects'), os.path.join(tempDir, 'manifests')
+            orphans = [os.path.join(objects, '01', '0123abcd.new'), os.path.join(objects, '4567abcd.new'),
+                       os.path.join(manifests, '01', '0123abcd.json.new')]
+            recent = os.path.join(objects, '89', '89abcdef.new')
+            stored = os.path.join(objects, '01', '0123abcd')
+            for path in orphans + [recent, stored]:
+                os.makedirs(os.path.dirname(path), exist_ok=True)
+                with open(path, 'wb') as f:
+                    f.write(b'object')
+            old = time.time() - ORPHAN_AGE - 60
+            for path in orphans + [stored]:
+                os.utime(path, (old, old))
+
+            store = ObjectStore(objects, 10000)
+            self.assertEqual(store.stats['objectOrphansRemoved'], 2)
+            self.assertEqual(len(store), 1)
+            # Possibly still written by another server
+            self.assertTrue(os.path.exists(recent))
+            self.assertEqual(ManifestStore(manifests).stats['manifestOrphansRemoved'], 1)
+            self.assertFalse(any(os.path.exists(path) for path in orphans))
+
+    def testEvictsLeastRecentlyUsed(self):
+        from clcache.server.__main__ import ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            source, target = os.path.join(tempDir, 'a.obj'), os.path.join(tempDir, 'b.obj')
+            with open(source, 'wb') as f:
+                f.write(b'x' * 40)
+
+            # Objects take whole allocation units
+            store = ObjectStore(os.path.join(tempDir, 'store'), 2 * ObjectStore.ALLOCATION_UNIT)
+            for key in ('aaa', 'bbb'):
+                store.put(key, source)
+            store.get('aaa', target)
+            store.put('ccc', source)
+            self.assertEqual((len(store), store.size()), (2, 2 * ObjectStore.ALLOCATION_UNIT))
+            self.assertEqual(store.stats['objectsEvicted'], 1)
+            with self.assertRaises(FileNotFoundError):
+                store.get('bbb', target)
+            store.get('aaa', target)
+
+            # ccc is the least recently used object now, but it is pinned
+            store.pin('ccc')
+            store.put('ddd', source)
+            self.assertEqual(store.stats['objectsEvicted'], 2)
+            with self.assertRaises(FileNotFoundError):
+                store.get('aaa', target)
+            store.unpin('ccc')
+            store.get('ccc', target)
+
+    def testLastUseSurvivesRestarts(self):
+        from clcache.server.__main__ import ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            source, target = os.path.join(tempDir, 'a.obj'), os.path.join(tempDir, 'b.obj')
+            with open(source, 'wb') as f:
+                f.write(b'x' * 40)
+
+            store = ObjectStore(os.path.join(tempDir, 'store'), 2 * ObjectStore.ALLOCATION_UNIT)
+            for key in ('aaa', 'bbb'):
+                store.put(key, source)
+            stored = os.stat(store.path('aaa'))
+            store.get('aaa', target)
+            # The stored file (and any hard link to it handed out) is left alone
+            self.assertEqual(os.stat(store.path('aaa')).st_mtime_ns, stored.st_mtime_ns)
+            store.close()
+
+            store = ObjectStore(os.path.join(tempDir, 'store'), 2 * ObjectStore.ALLOCATION_UNIT)
+            store.put('ccc', source)
+            with self.assertRaises(FileNotFoundError):
+                store.get('bbb', target)
+            store.get('aaa', target)
+
+    def testUsage(self):
+        from clcache.server.__main__ import ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            sources = [os.path.join(tempDir, 'src', project, 'build', 'a.obj') for project in ('one', 'two')]
+            for source in sources:
+                os.makedirs(os.path.dirname(source))
+                with open(source, 'wb') as f:
+                    f.write(b'object')
+
+            store = ObjectStore(os.path.join(tempDir, 'store'), 10 * ObjectStore.ALLOCATION_UNIT)
+            store.put('aaa', sources[0])
+            store.put('bbb', sources[0])
+            store.put('ccc', sources[1])
+            store.close()
+
+            usage = ObjectStore(os.path.join(tempDir, 'store'), 10 * ObjectStore.ALLOCATION_UNIT).usage(depth=1)
+            self.assertEqual(usage['objects'], 3)
+            self.assertEqual(usage['byAge']['<1d'], 3 * ObjectStore.ALLOCATION_UNIT)
+            depth = len([d for d in os.path.normcase(tempDir).split(os.sep) if d]) + 2
+            usage = ObjectStore(os.path.join(tempDir, 'store'), 10 * ObjectStore.ALLOCATION_UNIT).usage(depth)
+            self.assertEqual(usage['byOrigin'], {os.path.normcase(os.path.join(tempDir, 'src', 'one')): 8192,
+                                                 os.path.normcase(os.path.join(tempDir, 'src', 'two')): 4096})
+
+    def testHardLinks(self):
+        import stat
+        from clcache.server.__main__ import MATERIALIZE_HARDLINK, ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            source, target = os.path.join(tempDir, 'a.obj'), os.path.join(tempDir, 'b.obj')
+            with open(source, 'wb') as f:
+                f.write(b'object')
+
+            store = ObjectStore(os.path.join(tempDir, 'store'), 10000, MATERIALIZE_HARDLINK)
+            store.put('0123abcd', source)
+            store.put('4567abcd', source)
+            for _ in range(2):
+                self.assertEqual(store.get('0123abcd', target), 6)
+                self.assertTrue(os.path.samefile(target, store.path('0123abcd')))
+            # Writing to the link in place would change the object for all later hits
+            self.assertFalse(os.stat(target).st_mode & stat.S_IWRITE)
+            store.get('4567abcd', target)
+            self.assertTrue(os.path.samefile(target, store.path('4567abcd')))
+            self.assertFalse(os.stat(store.path('0123abcd')).st_mode & stat.S_IWRITE)
+            with self.assertRaises(FileNotFoundError):
+                store.get('0123abcd', os.path.join(tempDir, 'missing', 'b.obj'))
+            self.assertEqual(len(store), 2)
+
+    def testRemoteStore(self):
+        import pyuv
+        from clcache.server.__main__ import ObjectStore, RemoteStore
+
+        class FakeBackend:
+            def __init__(self):
+                self.objects = {}
+                self.failures = 1
+
+            def upload(self, key, path):
+                with open(path, 'rb') as f:
+                    self.objects[key] = f.read()
+
+            def download(self, key, path):
+                if self.failures:
+                    self.failures -= 1
+                    raise ConnectionError("flaky network")
+                if key not in self.objects:
+                    raise FileNotFoundError(key)
+                with open(path, 'wb') as f:
+                    f.write(self.objects[key])
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            source = os.path.join(tempDir, 'a.obj')
+            with open(source, 'wb') as f:
+                f.write(b'object')
+
+            backend = FakeBackend()
+            loop = pyuv.Loop.default_loop()
+            uploader = ObjectStore(os.path.join(tempDir, 'uploader'), 10000)
+            uploader.put('0123abcd', source)
+            RemoteStore(loop, backend, uploader).upload('0123abcd')
+            self.assertEqual(backend.objects, {'0123abcd': b'object'})
+
+            downloader = ObjectStore(os.path.join(tempDir, 'downloader'), 10000)
+            remoteStore = RemoteStore(loop, backend, downloader, backoff=0)
+            errors = []
+            remoteStore.download('0123abcd', errors.append)
+            remoteStore.download('4567abcd', errors.append)
+            self.assertIsNone(errors[0])
+            self.assertIsInstance(errors[1], FileNotFoundError)
+            self.assertEqual((remoteStore.stats['remoteHits'], remoteStore.stats['remoteMisses']), (1, 1))
+            self.assertEqual(downloader.get('0123abcd', os.path.join(tempDir, 'b.obj')), 6)
+
+            # An unreachable remote store is left alone for a while
+            backend.failures = RemoteStore.RETRIES
+            remoteStore.download('89abcdef', errors.append)
+            self.assertIsInstance(errors[2], ConnectionError)
+            self.assertTrue(remoteStore.isOffline())
+            remoteStore.download('89abcdef', errors.append)
+            self.assertIsInstance(errors[3], FileNotFoundError)
+            self.assertEqual(remoteStore.stats['remoteOfflineDownloads'], 1)
+
+    def testManifestStore(self):
+        from clcache.server.__main__ import ManifestStore
+        from clcache.server.protocol import ProtocolError
+
+        manifest = json.dumps({'entries': [{'includeFiles': ['a.h'], 'includesContentHash': '01',
+                                            'objectHash': '23'}]}, indent=2)
+        with tempfile.TemporaryDirectory() as tempDir:
+            store = ManifestStore(tempDir, maxCached=1)
+            store.put('0123abcd', manifest)
+            store.put('4567abcd', manifest)
+            self.assertEqual(store.get('4567abcd'), manifest)
+            # Dropped from memory, read from disk
+            self.assertEqual(store.get('0123abcd'), manifest)
+            self.assertEqual((store.stats['manifestHits'], store.stats['manifestReads']), (1, 1))
+            with self.assertRaises(FileNotFoundError):
+                store.get('89abcdef')
+            with self.assertRaises(ProtocolError):
+                store.put('89abcdef', '[]')
+
+            self.assertEqual(ManifestStore(tempDir).get('4567abcd'), manifest)
+
+class TestServerNamespaces(unittest.TestCase):
+    def testCachesAreCreatedOnDemand(self):
+        from clcache.server.__main__ import Namespaces
+
+        created = []
+        namespaces = Namespaces(lambda: created.append(object()) or created[-1])
+        self.assertIs(namespaces.get(), created[0])
+        msvc = namespaces.get('msvc1938-x64')
+        self.assertIsNot(msvc, created[0])
+        self.assertIs(namespaces.get('msvc1938-x64'), msvc)
+        self.assertEqual(len(created), 2)
+
+    def testInvalidNames(self):
+        from clcache.server.__main__ import Namespaces
+        from clcache.server.protocol import ProtocolError
+
+        namespaces = Namespaces(object)
+        for name in ['..\\x', 'a b', 'x' * 65, None, ['x']]:
+            with self.assertRaises(ProtocolError):
+                namespaces.get(name)
+
+
+    def testQuotas(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, Namespaces
+
+        self.assertEqual(Namespaces.parseQuotas(['msvc=100', '=5']), {'msvc': 100, '': 5})
+        for spec in ['msvc', 'msvc=many', 'a b=5', 'msvc=0']:
+            with self.assertRaises(ValueError):
+                Namespaces.parseQuotas([spec])
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            headers = []
+            for i in range(25):
+                headers.append(os.path.join(tempDir, '{}.h'.format(i)))
+                with open(headers[-1], 'w') as f:
+                    f.write(str(i))
+
+            loop = pyuv.Loop.default_loop()
+            # Journaled and revalidating, so that the side indexes of the entries fill up too
+            journaledVolumes = {os.path.splitdrive(tempDir)[0]: os.stat(tempDir).st_dev}
+            namespaces = Namespaces(lambda: HashCache(loop, [], True, revalidateCount=100,
+                                                      journaledVolumes=journaledVolumes), {'small': 10}, 20)
+            small, large = namespaces.get('small'), namespaces.get('large')
+            self.assertEqual(namespaces.get().maxEntries, 20)
+            for header in headers:
+                small.getFileHash(header)
+                large.getFileHash(header)
+
+            self.assertLessEqual(len(small), 10)
+            self.assertEqual(small.stats['quotaEvicted'], 25 - len(small))
+            self.assertLessEqual(len(large), 20)
+            self.assertGreater(large.stats['quotaEvicted'], 0)
+            # pylint: disable=protected-access
+            for cache in [small, large]:
+                self.assertEqual(len(cache._entriesByFileId), len(cache))
+                self.assertEqual(len(cache._entriesPerFileId), len(cache))
+                self.assertEqual(len(cache._recentlyUsed), len(cache))
+
+            # Below two entries, the slack still leaves the hashsum just stored
+            tiny = Namespaces(lambda: HashCache(loop, [], True), {}, 1).get()
+            for header in headers[:3]:
+                tiny.getFileHash(header)
+                self.assertEqual(len(tiny), 1)
+                self.assertIn(os.path.basename(header),
+                              tiny._watchedDirectories[os.path.normcase(tempDir)])  # pylint: disable=protected-access
+            self.assertEqual(tiny.stats['quotaEvicted'], 2)
+
+
+class TestServerTracer(unittest.TestCase):
+    def testInactive(self):
+        from clcache.server.__main__ import Tracer
+
+        self.assertIsNone(Tracer().newTrace('interactive', 1))
+
+    def testDumpsRequestedNumberOfTraces(self):
+        from clcache.server.__main__ import Tracer
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            traceFile = os.path.join(tempDir, 'trace.json')
+            tracer = Tracer()
+            tracer.start(2, traceFile)
+
+            first = tracer.newTrace('interactive', 1)
+            first.startFile('a.h')
+            first.fileEvent('read', 0.5)
+            second = tracer.newTrace('batch', 3)
+            self.assertIsNone(tracer.newTrace('interactive', 1))
+
+            tracer.finish(second)
+            self.assertFalse(os.path.exists(traceFile))
+            tracer.finish(first)
+
+            with open(traceFile) as f:
+                records = json.load(f)
+            self.assertEqual([r['priority'] for r in records], ['batch', 'interactive'])
+            self.assertEqual(records[1]['files'], [{'path': 'a.h', 'read': 0.5}])
+            self.assertIn('replied', records[0])
+
+    def testLogsSlowRequests(self):
+        from clcache.server.__main__ import Tracer
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            traceFile = os.path.join(tempDir, 'trace.json')
+            tracer = Tracer()
+            tracer.slowThreshold = 0
+            trace = tracer.newTrace('interactive', 3)
+            trace.startFile('a.h')
+            trace.fileEvent('cached', True)
+            for path, seconds in (('b.h', 0.002), ('c.h', 0.25)):
+                trace.startFile(path)
+                trace.fileEvent('open', seconds)
+                trace.fileEvent('read', seconds)
+
+            with self.assertLogs(level='WARNING') as logs:
+                tracer.finish(trace)
+            self.assertIn("3 paths (2 cold)", logs.output[0])
+            self.assertIn("slowest file c.h took 500.0 ms (open 250.0, read 250.0, hash 0.0)", logs.output[0])
+
+            # Requests traced for slowness only are not dumped
+            tracer.start(1, traceFile)
+            tracer.slowThreshold = 60
+            tracer.finish(tracer.newTrace('batch', 1))
+            tracer.finish(tracer.newTrace('interactive', 1))
+            with open(traceFile) as f:
+                self.assertEqual([r['priority'] for r in json.load(f)], ['batch'])
+
+
+
+class TestServerLatencyHistogram(unittest.TestCase):
+    def testBuckets(self):
+        from clcache.server.__main__ import LatencyHistogram
+
+        histogram = LatencyHistogram()
+        histogram.record(1, 0.001)
+        histogram.record(10, 0.2)
+        histogram.record(1000, 20)
+        histogram.record(1001, 0.01)
+        report = histogram.report()
+        self.assertEqual(report['1-10']['count'], 2)
+        self.assertEqual(report['1-10']['buckets']['0.001'], 1)
+        self.assertEqual(report['1-10']['buckets']['0.1'], 1)
+        self.assertEqual(report['1-10']['buckets']['0.25'], 2)
+        self.assertAlmostEqual(report['1-10']['sum'], 0.201)
+        self.assertEqual(report['11-100']['count'], 0)
+        self.assertEqual(report['101-1k']['buckets']['10'], 0)
+        self.assertEqual(report['101-1k']['buckets']['+Inf'], 1)
+        self.assertEqual(report['1k+']['buckets']['0.01'], 1)
+
+        metrics = histogram.prometheus().splitlines()
+        self.assertIn('# TYPE clcachesrv_first_byte_latency_seconds histogram', metrics)
+        self.assertIn('clcachesrv_first_byte_latency_seconds_bucket{paths="1-10",le="0.25"} 2', metrics)
+        self.assertIn('clcachesrv_first_byte_latency_seconds_count{paths="101-1k"} 1', metrics)
+
+
+class TestServerHashBenchmark(unittest.TestCase):
+    def testCapabilities(self):
+        from clcache.server.__main__ import HASHERS, HashBenchmark, registerHasher
+
+        def missingHasher():
+            raise ImportError("No module named 'missing'")
+
+        registerHasher('missing', missingHasher)
+        try:
+            capabilities = HashBenchmark(1024 * 1024).capabilities('md5')
+        finally:
+            del HASHERS['missing']
+        self.assertEqual(capabilities['current'], 'md5')
+        algorithms = {algorithm['name']: algorithm for algorithm in capabilities['algorithms']}
+        self.assertTrue(algorithms['md5']['available'])
+        self.assertGreater(algorithms['md5']['secondsPerGB'], 0)
+        self.assertEqual(algorithms['missing'], {'name': 'missing', 'available': False, 'secondsPerGB': None})
+
+
+class TestServerConnection(unittest.TestCase):
+    def setUp(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, IdleMonitor, Namespaces, PathMap, PipeServer
+
+        self.tempDir = tempfile.TemporaryDirectory()
+        self.headers = []
+        for i in range(120):
+            path = os.path.join(self.tempDir.name, 'header{}.h'.format(i))
+            with open(path, 'w') as f:
+                f.write('#define HEADER {}\n'.format(i))
+            self.headers.append(path)
+
+        loop = pyuv.Loop.default_loop()
+        self.server = PipeServer(loop, 'pipe', Namespaces(lambda: HashCache(loop, [], True)), PathMap.parse(None),
+                                 IdleMonitor(0))
+
+    def tearDown(self):
+        self.tempDir.cleanup()
+
+    def serve(self, *chunks, remote=False):
+        """Sends the chunks of a request, returns the response and the number of slices it took to hash"""
+        stream = MemoryStream()
+        self.server.serve(stream, 'tcp:192.0.2.1' if remote else 'test.exe', remote=remote)
+        for chunk in chunks:
+            stream.send(chunk)
+        slices = 0
+        scheduler = self.server.scheduler
+        while not stream.closed:
+            scheduler._onIdle(scheduler._idle)  # pylint: disable=protected-access
+            slices += 1
+        response = b''.join(stream.written)
+        self.assertTrue(response.endswith(protocol.TERMINATOR))
+        return response[:-len(protocol.TERMINATOR)], slices
+
+    def assertError(self, response, code):
+        self.assertTrue(response.startswith(protocol.ERROR_MARKER), response)
+        error = protocol.decodeError(response)
+        self.assertEqual(error.code, code)
+        return error
+
+    def md5(self, path):
+        import hashlib
+        with open(path, 'rb') as f:
+            return hashlib.md5(f.read()).hexdigest().encode('ascii')
+
+    def testCommands(self):
+        response, _ = self.serve(b'*version\x00')
+        self.assertEqual(json.loads(response.decode('utf-8'))['pid'], os.getpid())
+        response, _ = self.serve(b'*stats\x00')
+        self.assertIsInstance(json.loads(response.decode('utf-8')), dict)
+        self.assertError(self.serve(b'*frobnicate\x00')[0], protocol.ErrorCode.BAD_REQUEST)
+
+    def testToolchain(self):
+        compiler = os.path.join(self.tempDir.name, 'cl.exe')
+        with open(compiler, 'w') as f:
+            f.write('cl')
+        request = protocol.encodeRequest(['*toolchain ' + compiler, 'include=C:\\VC\\include'])
+        fingerprint = json.loads(self.serve(request)[0].decode('utf-8'))
+        self.assertEqual(fingerprint['files'], {'cl.exe': self.md5(compiler).decode('ascii')})
+        self.assertEqual(fingerprint['environment'],
+                         self.server.environmentDigest.digest({'INCLUDE': 'C:\\VC\\include'}))
+
+        self.assertError(self.serve(b'*toolchain\x00')[0], protocol.ErrorCode.BAD_REQUEST)
+        self.assertError(self.serve(protocol.encodeRequest(['*toolchain ' + compiler, 'INCLUDE']))[0],
+                         protocol.ErrorCode.BAD_REQUEST)
+        missing = os.path.join(self.tempDir.name, 'missing', 'cl.exe')
+        self.assertError(self.serve(protocol.encodeRequest(['*toolchain ' + missing]))[0],
+                         protocol.ErrorCode.NOT_FOUND)
+
+    def testEnvironment(self):
+        request = protocol.encodeRequest(['*environment', 'include=C:\\VC\\include', 'LIB=C:\\VC\\lib',
+                                          'USERNAME=builder'])
+        response = json.loads(self.serve(request)[0].decode('utf-8'))
+        self.assertEqual(response['variables'], ['INCLUDE', 'LIB'])
+        request = protocol.encodeRequest(['*environment', 'INCLUDE=C:\\VC\\include\\', 'LIB=C:\\VC\\lib'])
+        self.assertEqual(json.loads(self.serve(request)[0].decode('utf-8'))['digest'], response['digest'])
+
+        self.assertError(self.serve(protocol.encodeRequest(['*environment', 'PATH']))[0],
+                         protocol.ErrorCode.BAD_REQUEST)
+
+    def testExportUsage(self):
+        import csv
+        header = {'pathMap': {os.path.join(os.sep, 'virtual', 'src'): self.tempDir.name}}
+        mapped = os.path.join(os.sep, 'virtual', 'src', 'header0.h')
+        self.assertEqual(self.serve(protocol.encodeRequest([mapped], header))[0], self.md5(self.headers[0]))
+
+        # Paths are exported the way the client asking for them maps them
+        target = os.path.join(self.tempDir.name, 'usage.csv')
+        for requestHeader, path in ((header, mapped), (None, self.headers[0])):
+            self.assertEqual(self.serve(protocol.encodeRequest(['*export-usage ' + target], requestHeader))[0], b'')
+            with open(target, newline='') as f:
+                rows = list(csv.DictReader(f))
+            self.assertEqual([row['path'] for row in rows], [os.path.normcase(path)])
+
+    def testSetLogLevel(self):
+        import logging
+
+        logger = logging.getLogger()
+        original = logger.level
+        try:
+            logger.setLevel(logging.INFO)
+            response, _ = self.serve(b'*set-log-level DEBUG\x00')
+            self.assertEqual(json.loads(response.decode('utf-8')), {'level': 'debug', 'previous': 'info'})
+            self.assertEqual(logger.level, logging.DEBUG)
+            self.assertError(self.serve(b'*set-log-level trace\x00')[0], protocol.ErrorCode.BAD_REQUEST)
+            self.assertEqual(logger.level, logging.DEBUG)
+        finally:
+            logger.setLevel(original)
+
+    def testMalformedRequests(self):
+        header = self.headers[0].encode('utf-8')
+        for request in (b'>{"priority": \n' + header + b'\x00',
+                        b'>[]\n' + header + b'\x00',
+                        header + b'\x00' + header + b'\x00',
+                        protocol.encodeRequest([self.headers[0]], {'priority': 'urgent'}),
+                        protocol.encodeRequest([self.headers[0]], {'cwd': 'src'}),
+                        b'>{"length": 3}\n' + header + b'\x00',
+                        b'\xff\xfe\x00'):
+            self.assertError(self.serve(request)[0], protocol.ErrorCode.BAD_REQUEST)
+
+    def testRequestInChunks(self):
+        request = protocol.encodeRequest(self.headers[:2])
+        response, _ = self.serve(*(request[i:i + 1] for i in range(len(request))))
+        self.assertEqual(response, self.md5(self.headers[0]) + b'\n' + self.md5(self.headers[1]))
+
+    def testLargeBatch(self):
+        from clcache.server.__main__ import Scheduler
+
+        paths = self.headers * 5
+        response, slices = self.serve(protocol.encodeRequest(paths, {'priority': protocol.Priority.BATCH}))
+        self.assertEqual(response.split(b'\n'), [self.md5(path) for path in paths])
+        self.assertEqual(slices, len(paths) // Scheduler.SLICE_SIZE)
+
+    def testMissingFile(self):
+        missing = os.path.join(self.tempDir.name, 'missing.h')
+        error = self.assertError(self.serve(protocol.encodeRequest([self.headers[0], missing]))[0],
+                                 protocol.ErrorCode.NOT_FOUND)
+        self.assertEqual(error.filename, missing)
+
+    def testSignedResponse(self):
+        import base64
+        from clcache.server.__main__ import ResponseSigner
+
+        try:
+            from cryptography.exceptions import InvalidSignature
+            from cryptography.hazmat.primitives import serialization
+            from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey, Ed25519PublicKey
+        except ImportError:
+            self.skipTest("requires the cryptography package")
+
+        request = protocol.encodeRequest(self.headers[:2], {'sign': True})
+        self.assertError(self.serve(request)[0], protocol.ErrorCode.BAD_REQUEST)
+        self.assertError(self.serve(b'*public-key\x00')[0], protocol.ErrorCode.BAD_REQUEST)
+
+        keyFile = os.path.join(self.tempDir.name, 'key.bin')
+        with open(keyFile, 'wb') as f:
+            f.write(Ed25519PrivateKey.generate().private_bytes(serialization.Encoding.Raw,
+                                                               serialization.PrivateFormat.Raw,
+                                                               serialization.NoEncryption()))
+        self.server.signer = ResponseSigner(keyFile)
+        publicKey = Ed25519PublicKey.from_public_bytes(base64.b64decode(self.serve(b'*public-key\x00')[0]))
+
+        lines = self.serve(request)[0].split(b'\n')
+        self.assertEqual(lines[:2], [self.md5(self.headers[0]), self.md5(self.headers[1])])
+        self.assertTrue(lines[2].startswith(protocol.SIGNATURE_MARKER.encode('ascii')))
+        signature = base64.b64decode(lines[2][len(protocol.SIGNATURE_MARKER):])
+        hashes = [line.decode('ascii') for line in lines[:2]]
+        publicKey.verify(signature, protocol.signedMessage(self.headers[:2], hashes))
+        # The signature binds the hashes to the paths asked for
+        with self.assertRaises(InvalidSignature):
+            publicKey.verify(signature, protocol.signedMessage(self.headers[1::-1], hashes[::-1]))
+
+        # Unsigned requests are answered as before
+        self.assertEqual(len(self.serve(protocol.encodeRequest(self.headers[:2]))[0].split(b'\n')), 2)
+
+    def testRemoteClients(self):
+        from unittest import mock
+        from clcache.server.__main__ import Connection, isLoopbackAddress
+
+        request = protocol.encodeRequest(self.headers[:2])
+        self.assertEqual(self.serve(request, remote=True)[0],
+                         self.md5(self.headers[0]) + b'\n' + self.md5(self.headers[1]))
+        response, _ = self.serve(b'*version\x00', remote=True)
+        self.assertEqual(json.loads(response.decode('utf-8'))['pid'], os.getpid())
+
+        # Commands acting on the server or on files by name are for local clients only
+        target = os.path.join(self.tempDir.name, 'target')
+        for command in ('handoff ' + target, 'export-usage ' + target, 'trace-next 1 ' + target, 'clear', 'gc 1',
+                        'put-object 0123abcd', 'get-object 0123abcd', 'put-manifest 0123abcd',
+                        'get-manifest 0123abcd', 'watch ' + self.tempDir.name, 'set-log-level debug', 'stats',
+                        'find-include a.h', 'warm ' + self.tempDir.name, 'cache-usage',
+                        'toolchain ' + self.headers[0]):
+            request = protocol.encodeRequest(['*' + command, self.headers[0], target])
+            self.assertError(self.serve(request, remote=True)[0], protocol.ErrorCode.ACCESS_DENIED)
+        self.assertFalse(os.path.exists(target))
+        self.assertFalse(self.server._shuttingDown)  # pylint: disable=protected-access
+
+        with mock.patch.object(Connection, 'MAX_REMOTE_REQUEST_SIZE', 100):
+            request = protocol.encodeRequest(self.headers)
+            self.assertError(self.serve(request[:60], request[60:], remote=True)[0], protocol.ErrorCode.BAD_REQUEST)
+            self.assertEqual(len(self.serve(request)[0].split(b'\n')), len(self.headers))
+
+        self.assertTrue(isLoopbackAddress('127.0.0.1'))
+        self.assertTrue(isLoopbackAddress('::1'))
+        self.assertTrue(isLoopbackAddress('localhost'))
+        self.assertFalse(isLoopbackAddress('0.0.0.0'))
+        self.assertFalse(isLoopbackAddress('buildserver'))
+
+    def testClientHangsUp(self):
+        import pyuv
+
+        stream = MemoryStream()
+        self.server.serve(stream, 'test.exe')
+        stream.send(protocol.encodeRequest(self.headers[:1])[:-1])
+        stream.hangUp(pyuv.errno.UV_EOF)
+        self.assertTrue(stream.closed)
+        self.assertEqual(stream.written, [])
+        self.assertEqual(self.server.requestsInFlight(), [])
+
+
+class TestServerToolchains(unittest.TestCase):
+    def testFingerprint(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, Toolchains
+
+        toolchains = Toolchains()
+        cache = HashCache(pyuv.Loop.default_loop(), [], True)
+        with tempfile.TemporaryDirectory() as tempDir:
+            compiler = os.path.join(tempDir, 'cl.exe')
+            for name in ('cl.exe', 'c1xx.dll', 'c2.dll', 'readme.txt'):
+                with open(os.path.join(tempDir, name), 'w') as f:
+                    f.write(name)
+
+            fingerprint = toolchains.fingerprint(compiler, 'environment', cache)
+            self.assertEqual(sorted(fingerprint['files']), ['c1xx.dll', 'c2.dll', 'cl.exe'])
+            self.assertIsNone(fingerprint['version'])
+
+            # The environment makes for another digest, but the installation is the same
+            other = toolchains.fingerprint(compiler, 'other environment', cache)
+            self.assertEqual(other['installation'], fingerprint['installation'])
+            self.assertNotEqual(other['digest'], fingerprint['digest'])
+            self.assertEqual(toolchains.stats['toolchainProbes'], 1)
+            self.assertEqual(toolchains.stats['toolchainChanges'], 0)
+
+            with open(os.path.join(tempDir, 'c1xx.dll'), 'w') as f:
+                f.write('updated front end')
+            # As the directory watcher would
+            cache.clear()
+            changed = toolchains.fingerprint(compiler, 'environment', cache)
+            self.assertNotEqual(changed['digest'], fingerprint['digest'])
+            self.assertEqual(toolchains.stats['toolchainProbes'], 1)
+            self.assertEqual(toolchains.stats['toolchainChanges'], 1)
+
+
+class TestServerEnvironmentDigest(unittest.TestCase):
+    def testNormalize(self):
+        from clcache.server.__main__ import EnvironmentDigest
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            environment = {
+                'Include': r' "C:\VC\include\";;C:\VC\include;C:\SDK\include ',
+                'Path': tempDir + ';' + os.path.join(tempDir, 'missing'),
+                'CL': '/DNDEBUG',
+                'USERNAME': 'builder',
+            }
+            normalized = EnvironmentDigest().normalize(environment)
+            self.assertEqual(normalized, {
+                'INCLUDE': os.path.normcase(r'C:\VC\include') + ';' + os.path.normcase(r'C:\SDK\include'),
+                'PATH': os.path.normcase(tempDir),
+                'CL': '/DNDEBUG',
+            })
+            self.assertEqual(EnvironmentDigest().normalize(environment, ('CL', 'USERNAME')),
+                             {'CL': '/DNDEBUG', 'USERNAME': 'builder'})
+
+    def testDigest(self):
+        from clcache.server.__main__ import EnvironmentDigest
+
+        digest = EnvironmentDigest(['*'], ['user*', 'TMP'])
+        self.assertEqual(digest.digest({'LIB': r'C:\VC\lib', 'USERNAME': 'alice', 'TMP': r'C:\Temp'}),
+                         digest.digest({'lib': 'C:\\VC\\lib\\', 'UserName': 'bob'}))
+        self.assertNotEqual(digest.digest({'LIB': r'C:\VC\lib'}), digest.digest({'LIB': r'C:\VC\lib;C:\SDK\lib'}))
+        # Excluded variables are left out even if asked for by name
+        self.assertEqual(digest.normalize({'TMP': r'C:\Temp'}, ('TMP',)), {})
+
+
+class TestServerResponseSigner(unittest.TestCase):
+    def setUp(self):
+        try:
+            import cryptography  # pylint: disable=unused-import
+        except ImportError:
+            self.skipTest("requires the cryptography package")
+        self.tempDir = tempfile.TemporaryDirectory()
+
+    def tearDown(self):
+        self.tempDir.cleanup()
+
+    def writeKey(self, name, data):
+        keyFile = os.path.join(self.tempDir.name, name)
+        with open(keyFile, 'wb') as f:
+            f.write(data)
+        return keyFile
+
+    def testKeyFormats(self):
+        import base64
+        from cryptography.hazmat.primitives import serialization
+        from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+        from clcache.server.__main__ import ResponseSigner
+
+        key = Ed25519PrivateKey.generate()
+        raw = self.writeKey('key.bin', key.private_bytes(serialization.Encoding.Raw, serialization.PrivateFormat.Raw,
+                                                         serialization.NoEncryption()))
+        pem = self.writeKey('key.pem', key.private_bytes(serialization.Encoding.PEM,
+                                                         serialization.PrivateFormat.PKCS8,
+                                                         serialization.NoEncryption()))
+        publicKey = base64.b64encode(key.public_key().public_bytes(serialization.Encoding.Raw,
+                                                                   serialization.PublicFormat.Raw)).decode('ascii')
+        for keyFile in (raw, pem):
+            signer = ResponseSigner(keyFile)
+            self.assertEqual(signer.publicKey, publicKey)
+            key.public_key().verify(base64.b64decode(signer.sign(b'message')), b'message')
+
+    def testOtherKeyTypes(self):
+        from cryptography.hazmat.primitives import serialization
+        from cryptography.hazmat.primitives.asymmetric import ec
+        from clcache.server.__main__ import ResponseSigner
+
+        keyFile = self.writeKey('key.pem', ec.generate_private_key(ec.SECP256R1()).private_bytes(
+            serialization.Encoding.PEM, serialization.PrivateFormat.PKCS8, serialization.NoEncryption()))
+        with self.assertRaisesRegex(ValueError, 'does not contain an Ed25519 private key'):
+            ResponseSigner(keyFile)
+        with self.assertRaises(ValueError):
+            ResponseSigner(self.writeKey('garbage.pem', b'not a key'))
+
+
+class TestServerConnectionLimit(unittest.TestCase):
+    def testQueueAndReject(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, IdleMonitor, Namespaces, PathMap, PipeServer
+
+        loop = pyuv.Loop.default_loop()
+        server = PipeServer(loop, 'pipe', Namespaces(lambda: HashCache(loop, [], True)), PathMap.parse(None),
+                            IdleMonitor(0), maxConnections=1, maxQueuedConnections=1)
+        served, queued, rejected = (MemoryStream() for _ in range(3))
+        for stream in (served, queued, rejected):
+            server.serve(stream, 'ninja.exe')
+        self.assertFalse(queued.isReading())
+        self.assertEqual(server.queuedConnections(), 1)
+        self.assertEqual(server.stats['rejectedConnections'], 1)
+
+        rejected.send(b'*version\x00')
+        error = protocol.decodeError(rejected.written[0][:-1])
+        self.assertEqual(error.code, protocol.ErrorCode.OVERLOADED)
+        self.assertEqual(error.retryAfter, PipeServer.RETRY_AFTER)
+        self.assertEqual(server.queuedConnections(), 1)
+
+        # The queued client is served once the served one is done
+        served.send(b'*version\x00')
+        self.assertIn(b'version', served.written[0])
+        self.assertEqual(server.queuedConnections(), 0)
+        queued.send(b'*version\x00')
+        self.assertIn(b'version', queued.written[0])
+
+
+class TestServerWatch(unittest.TestCase):
+    def testChangesArePushed(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, IdleMonitor, Namespaces, PathMap, PipeServer
+
+        loop = pyuv.Loop.default_loop()
+        for journaled in (False, True):
+            with tempfile.TemporaryDirectory() as tempDir:
+                header = os.path.join(tempDir, 'a.h')
+                with open(header, 'w') as f:
+                    f.write('a')
+                journaledVolumes = {os.path.splitdrive(tempDir)[0]: os.stat(tempDir).st_dev} if journaled else {}
+                namespaces = Namespaces(lambda: HashCache(loop, [], False, journaledVolumes=journaledVolumes))
+                server = PipeServer(loop, 'pipe', namespaces, PathMap.parse(None), IdleMonitor(0))
+                cache = namespaces.get()
+                # Cached before anyone subscribed, i.e. without a watcher on a journaled volume
+                cache.getFileHash(header)
+
+                stream = MemoryStream()
+                server.serve(stream, 'devenv.exe')
+                stream.send(protocol.encodeRequest(['*watch ' + tempDir]))
+                self.assertEqual(stream.written, ['~watching {}\n'.format(tempDir).encode('utf-8')])
+
+                # What the watcher of the directory reports once a.h changed
+                # pylint: disable=protected-access
+                watchers = [handle for handle in cache._handlers if handle.path == os.path.normcase(tempDir)]
+                self.assertEqual(len(watchers), 1, journaled)
+                cache._onPathChange(watchers[0], 'a.h', pyuv.fs.UV_CHANGE, None)
+                self.assertEqual(stream.written[-1], '~changed {}\n'.format(os.path.normcase(header)).encode('utf-8'))
+                self.assertEqual(len(cache), 0)
+                stream.close()
+
+    def testNetworkDirectories(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, IdleMonitor, Namespaces, NetworkPolicy, PathMap, PipeServer
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            loop = pyuv.Loop.default_loop()
+            cache = HashCache(loop, [], False, networkPolicy=NetworkPolicy(isNetworkDrive=lambda drive: True))
+            server = PipeServer(loop, 'pipe', Namespaces(lambda: cache), PathMap.parse(None), IdleMonitor(0))
+            stream = MemoryStream()
+            server.serve(stream, 'devenv.exe')
+            stream.send(protocol.encodeRequest(['*watch ' + tempDir]))
+            self.assertEqual(protocol.decodeError(stream.written[0][:-1]).code, protocol.ErrorCode.BAD_REQUEST)
+            self.assertEqual(cache._handlers, [])  # pylint: disable=protected-access
+
+
+class TestServerHeartbeats(unittest.TestCase):
+    def testHeartbeats(self):
+        import time
+        import pyuv
+        from clcache.server.__main__ import HEARTBEAT_MISSES, HashCache, IdleMonitor, Namespaces, PathMap, PipeServer
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            loop = pyuv.Loop.default_loop()
+            server = PipeServer(loop, 'pipe', Namespaces(lambda: HashCache(loop, [], False)), PathMap.parse(None),
+                                IdleMonitor(0))
+            silent, chatty = MemoryStream(), MemoryStream()
+            for stream in (silent, chatty):
+                server.serve(stream, 'devenv.exe')
+                stream.send(protocol.encodeRequest(['*watch ' + tempDir]))
+
+            chatty.send(b'~heartbeat\n')
+            server.sendHeartbeats(1.0)
+            self.assertEqual(silent.written[-1], b'~heartbeat\n')
+            self.assertEqual(chatty.written[-1], b'~heartbeat\n')
+
+            # Only subscribers which sent heartbeats are expected to keep sending them
+            server.sendHeartbeats(1.0, time.monotonic() + HEARTBEAT_MISSES + 1)
+            self.assertFalse(silent.closed)
+            self.assertTrue(chatty.closed)
+            self.assertEqual(server.stats['heartbeatTimeouts'], 1)
+
+
+class TestServerReplay(unittest.TestCase):
+    def testRecordAndReplay(self):
+        from clcache.server.__main__ import RequestRecorder, replayRequests
+
+        requests = [
+            protocol.encodeRequest([r"C:\src\a.h", r"C:\src\b.h"]),
+            protocol.encodeRequest(['*handoff C:\\snapshot.json']),
+            protocol.encodeRequest([r"C:\src\missing.h"], {'priority': 'batch'}),
+        ]
+        with tempfile.TemporaryDirectory() as tempDir:
+            captureFile = os.path.join(tempDir, 'capture.jsonl')
+            recorder = RequestRecorder(captureFile)
+            for request in requests:
+                recorder.record(request)
+            recorder.close()
+
+            sent = []
+
+            def send(data):
+                sent.append(data)
+                return b'!{}\x00' if b'missing' in data else b'0123\n4567\x00'
+
+            summary = replayRequests(captureFile, 2, send)
+        self.assertEqual(sorted(sent), sorted([requests[0], requests[2]]))
+        self.assertEqual(summary['requests'], 2)
+        self.assertEqual(summary['skipped'], 1)
+        self.assertEqual(summary['errors'], 1)
+        self.assertIsNotNone(summary['latency']['p99'])
+
+
+class TestServerDoctor(unittest.TestCase):
+    def testScanningSuspected(self):
+        from clcache.server.__main__ import Doctor
+
+        self.assertTrue(Doctor.scanningSuspected(0.5, 0.01))
+        self.assertFalse(Doctor.scanningSuspected(0.012, 0.002))
+        self.assertFalse(Doctor.scanningSuspected(0.05, 0.04))
+
+    def testChecks(self):
+        from clcache.server.__main__ import Doctor
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            doctor = Doctor(tempDir)
+            self.assertEqual(doctor.checkClock()[0], Doctor.OK)
+            self.assertIn(doctor.checkScanning()[0], (Doctor.OK, Doctor.WARNING))
+            self.assertEqual(os.listdir(tempDir), [])
+
+            out = io.StringIO()
+            doctor.run(out)
+            self.assertIn('[ok] clock: ', out.getvalue())
+
+
+class TestServerCrashReporter(unittest.TestCase):
+    def testReport(self):
+        import pyuv
+        from clcache.server.__main__ import CrashReporter, HashCache, IdleMonitor, Namespaces, PathMap, PipeServer
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            header = os.path.join(tempDir, 'a.h')
+            with open(header, 'w') as f:
+                f.write("#define A")
+
+            loop = pyuv.Loop.default_loop()
+            namespaces = Namespaces(lambda: HashCache(loop, [], True))
+            namespaces.get().getFileHash(header)
+            server = PipeServer(loop, 'pipe', namespaces, PathMap.parse(None), IdleMonitor(0))
+            server.serve(MemoryStream(), 'ninja.exe')
+
+            reporter = CrashReporter(os.path.join(tempDir, 'crashes'), server)
+            os.makedirs(reporter.directory)
+            try:
+                raise ValueError("broken")
+            except ValueError:
+                reportFile = reporter.report("uncaught exception", sys.exc_info())
+
+            with open(reportFile) as f:
+                report = json.load(f)
+            self.assertEqual(report['reason'], "uncaught exception")
+            self.assertIn("ValueError: broken", report['traceback'])
+            self.assertEqual(report['hashsums'], {'': 1})
+            self.assertEqual([request['client'] for request in report['requestsInFlight']], ['ninja.exe'])
+
+
+class TestServerScanAdvisor(unittest.TestCase):
+    def testSlowDirectory(self):
+        from clcache.server.__main__ import ScanAdvisor
+
+        advisor = ScanAdvisor()
+        for _ in range(200):
+            self.assertFalse(advisor.record('c:\\toolchain', 0.0001))
+        # A few slow reads are noise, most reads being slow makes a directory suspect
+        for _ in range(ScanAdvisor.MIN_READS):
+            advisor.record('c:\\src', 0.0001)
+            advisor.record('c:\\src', 0.0001)
+            self.assertFalse(advisor.record('c:\\src', 0.1))
+        for _ in range(ScanAdvisor.MIN_READS - 1):
+            self.assertFalse(advisor.record('c:\\build', 0.1))
+        self.assertTrue(advisor.record('c:\\build', 0.1))
+        self.assertFalse(advisor.record('c:\\build', 0.1))
+
+        self.assertEqual(advisor.suspects, ['c:\\build'])
+        self.assertEqual(advisor.report()['c:\\build'], {'reads': ScanAdvisor.MIN_READS + 1,
+                                                         'slowReads': ScanAdvisor.MIN_READS + 1})
+
+
+class TestServerScheduler(unittest.TestCase):
+    def testBatchJobsInBackground(self):
+        import pyuv
+        from clcache.server.__main__ import Scheduler
+
+        class FakeIoPriority:
+            def __init__(self):
+                self.modes = []
+
+            def setBackground(self, background):
+                self.modes.append(background)
+
+        class FakeJob:
+            retryAfter = None
+
+            def __init__(self):
+                self.connection = self
+
+            def isClosed(self):
+                return False
+
+            def step(self, count):
+                return True
+
+        ioPriority = FakeIoPriority()
+        scheduler = Scheduler(pyuv.Loop.default_loop(), ioPriority=ioPriority)
+        scheduler.submit(FakeJob(), protocol.Priority.BATCH)
+        scheduler.submit(FakeJob(), protocol.Priority.INTERACTIVE)
+        for _ in range(2):
+            scheduler._onIdle(scheduler._idle)  # pylint: disable=protected-access
+
+        # Interactive jobs go first; the thread leaves background mode once there is nothing left to do
+        self.assertEqual(ioPriority.modes, [False, True, False])
+
+
+class TestServerSnapshotDiff(unittest.TestCase):
+    def testJournalAgainstHandoffFile(self):
+        import pyuv
+        from clcache.server.__main__ import HashCache, Journal, Namespaces, diffSnapshots, hashTag, loadSnapshot, \
+            printSnapshotDiff
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            a, b = os.path.join(tempDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            for path in (a, b):
+                with open(path, 'w') as f:
+                    f.write(path)
+            loop = pyuv.Loop.default_loop()
+            namespaces = Namespaces(lambda: HashCache(loop, [], True, journaled=True))
+            namespaces.get().getFileHash(a)
+            namespaces.get().getFileHash(b)
+            journalFile = os.path.join(tempDir, 'journal.db')
+            journal = Journal(journalFile)
+            journal.append(namespaces)
+            journal.close()
+
+            snapshot = namespaces.snapshot()
+            dirname = os.path.normcase(tempDir)
+            entries = snapshot[''][dirname]
+            entries['b.h'] = ['0' * 32] + entries['b.h'][1:]
+            entries['c.h'] = ['1' * 32, 0, 3, hashTag('md5')]
+            del entries['a.h']
+            snapshot['msvc'] = {dirname: {'a.h': ['2' * 32, 0, 3, hashTag('md5')]}}
+            handoffFile = os.path.join(tempDir, 'handoff.json')
+            with open(handoffFile, 'w') as f:
+                json.dump({'version': '4.2.0', 'namespaces': snapshot}, f)
+
+            diff = diffSnapshots(loadSnapshot(handoffFile), loadSnapshot(journalFile))
+            self.assertEqual([entry[:2] for entry in diff['added']], [('', os.path.join(dirname, 'a.h'))])
+            self.assertEqual([entry[:2] for entry in diff['removed']],
+                             [('', os.path.join(dirname, 'c.h')), ('msvc', os.path.join(dirname, 'a.h'))])
+            self.assertEqual([entry[:2] for entry in diff['changed']], [('', os.path.join(dirname, 'b.h'))])
+            self.assertEqual(diff['identical'], 0)
+
+            out = io.StringIO()
+            printSnapshotDiff(diff, out)
+            self.assertIn("~ {}: {} -> ".format(os.path.join(dirname, 'b.h'), '0' * 32), out.getvalue())
+            self.assertTrue(out.getvalue().endswith("1 added, 2 removed, 1 changed, 0 identical\n"))
+
+            self.assertEqual(diffSnapshots(loadSnapshot(journalFile), namespaces.snapshot())['identical'], 2)
+
+
+class TestServerFsck(unittest.TestCase):
+    def testJournal(self):
+        import hashlib
+        from clcache.server.__main__ import Fsck, Journal, hashTag
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            a, b = os.path.join(tempDir, 'a.h'), os.path.join(tempDir, 'b.h')
+            for path in (a, b):
+                with open(path, 'wb') as f:
+                    f.write(b'#pragma once\n')
+            journalFile = os.path.join(tempDir, 'journal.db')
+            journal = Journal(journalFile)
+            with journal._db:  # pylint: disable=protected-access
+                journal._db.executemany(Journal.INSERT, [  # pylint: disable=protected-access
+                    ['', tempDir, 'a.h', hashlib.md5(b'#pragma once\n').hexdigest(), os.stat(a).st_mtime_ns, 13,
+                     hashTag('md5')],
+                    ['', tempDir, 'b.h', '0' * 32, os.stat(b).st_mtime_ns, 13, hashTag('md5')],
+                    ['', tempDir, 'c.h', '1' * 32, 0, 13, hashTag('md5')],
+                    ['', tempDir, 'd.h', 'not a hashsum', 0, 13, hashTag('md5')],
+                ])
+            journal.close()
+
+            out = io.StringIO()
+            self.assertFalse(Fsck(journal=journalFile).run(out))
+            self.assertIn("journal {}: 4 checked, 2 corrupt, 1 stale\n".format(journalFile), out.getvalue())
+            self.assertTrue(out.getvalue().endswith("found 3 problems, run with --repair to fix them\n"))
+
+            self.assertTrue(Fsck(journal=journalFile, repair=True).run(io.StringIO()))
+            journal = Journal(journalFile, readOnly=True)
+            self.assertEqual(list(journal.replay()[''][tempDir]), ['a.h'])
+            journal.close()
+
+            out = io.StringIO()
+            self.assertTrue(Fsck(journal=journalFile).run(out))
+            self.assertTrue(out.getvalue().endswith("no problems found\n"))
+
+            # A journal which is no database at all is quarantined as a whole
+            with open(journalFile, 'wb') as f:
+                f.write(Journal.SQLITE_HEADER + b'garbage')
+            self.assertTrue(Fsck(journal=journalFile, repair=True).run(io.StringIO()))
+            self.assertFalse(os.path.exists(journalFile))
+            self.assertTrue(os.path.exists(os.path.join(journalFile + Fsck.QUARANTINE_SUFFIX, 'journal.db')))
+
+    def testStores(self):
+        from clcache.server.__main__ import Fsck, ManifestStore, ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            objects, manifests = os.path.join(tempDir, 'objects'), os.path.join(tempDir, 'manifests')
+            source = os.path.join(tempDir, 'a.obj')
+            with open(source, 'wb') as f:
+                f.write(b'object')
+            store = ObjectStore(objects, 100000)
+            store.put('0123abcd', source)
+            store.put('4567abcd', source)
+            store.close()
+            os.remove(store.path('4567abcd'))
+            os.makedirs(os.path.dirname(store.path('89abcdef')))
+            open(store.path('89abcdef'), 'wb').close()
+            os.makedirs(os.path.join(objects, 'zz'))
+            with open(os.path.join(objects, 'zz', '0123ffff'), 'wb') as f:
+                f.write(b'object')
+
+            manifestStore = ManifestStore(manifests)
+            manifestStore.put('fedcba98', json.dumps({'entries': [{'objectHash': '0123abcd'},
+                                                                 {'objectHash': '4567abcd'}]}))
+            os.makedirs(os.path.join(manifests, '76'))
+            with open(os.path.join(manifests, '76', '76543210.json'), 'w') as f:
+                f.write('{"entries": [')
+
+            out = io.StringIO()
+            fsck = Fsck(objectStore=objects, manifestStore=manifests)
+            self.assertFalse(fsck.run(out))
+            # The origin and the last use of the object removed
+            self.assertIn("object store {}: 3 checked, 2 corrupt, 2 dangling\n".format(objects), out.getvalue())
+            self.assertIn("manifest store {}: 2 checked, 1 corrupt, 1 dangling\n".format(manifests), out.getvalue())
+
+            self.assertTrue(Fsck(objectStore=objects, manifestStore=manifests, repair=True).run(io.StringIO()))
+            quarantine = objects + Fsck.QUARANTINE_SUFFIX
+            self.assertTrue(os.path.exists(os.path.join(quarantine, '89', '89abcdef')))
+            self.assertTrue(os.path.exists(os.path.join(quarantine, 'zz', '0123ffff')))
+            self.assertTrue(os.path.exists(os.path.join(manifests + Fsck.QUARANTINE_SUFFIX, '76', '76543210.json')))
+            self.assertEqual(json.loads(ManifestStore(manifests).get('fedcba98')),
+                             {'entries': [{'objectHash': '0123abcd'}]})
+            for name in (ObjectStore.ORIGINS_FILE, ObjectStore.LAST_USE_FILE):
+                with open(os.path.join(objects, name)) as f:
+                    self.assertEqual(list(json.load(f)), ['0123abcd'])
+            self.assertEqual(len(ObjectStore(objects, 100000)), 1)
+
+            out = io.StringIO()
+            self.assertTrue(Fsck(objectStore=objects, manifestStore=manifests).run(out))
+            self.assertTrue(out.getvalue().endswith("no problems found\n"))
+
+    def testOrphans(self):
+        import time
+        from clcache.server.__main__ import Fsck, ORPHAN_AGE, ObjectStore
+
+        with tempfile.TemporaryDirectory() as tempDir:
+            objects = os.path.join(tempDir, 'objects')
+            source = os.path.join(tempDir, 'a.obj')
+            with open(source, 'wb') as f:
+                f.write(b'object')
+            store = ObjectStore(objects, 100000)
+            store.put('0123abcd', source)
+            store.close()
+            orphans = [os.path.join(objects, '01', '4567abcd.new'), os.path.join(objects, 'origins.json.new')]
+            # Possibly still being written by a running server
+            recent = os.path.join(objects, '01', '89abcdef.new')
+            for path in orphans + [recent]:
+                with open(path, 'wb') as f:
+                    f.write(b'partial')
+            now = time.time()
+            for path in orphans:
+                os.utime(path, (now - ORPHAN_AGE - 1, now - ORPHAN_AGE - 1))
+
+            out = io.StringIO()
+            self.assertFalse(Fsck(objectStore=objects, now=now).run(out))
+            self.assertIn("object store {}: 1 checked, 2 orphans\n".format(objects), out.getvalue())
+            self.assertTrue(Fsck(objectStore=objects, repair=True, now=now).run(io.StringIO()))
+            self.assertFalse(any(os.path.exists(path) for path in orphans))
+            self.assertTrue(os.path.exists(recent))
+            self.assertTrue(Fsck(objectStore=objects, now=now).run(io.StringIO()))
+
+
 if __name__ == '__main__':
     unittest.TestCase.longMessage = True
     unittest.main()